@@ -0,0 +1,51 @@
+use clap::{Parser, Subcommand};
+
+use crate::discovery::Subnet;
+
+/// A service designed to work with NUT (Network UPS Tools) to automatically wake devices after a power outage
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(long, global = true, default_value = "/nutjob/config.yaml")]
+    pub config: String,
+
+    /// Path to the state file
+    #[arg(long, global = true, default_value = "/nutjob/state")]
+    pub state: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Monitor the UPS and wake devices once power is restored (default)
+    Run,
+    /// Load and validate the configuration file, printing any problems found
+    CheckConfig,
+    /// Print the persisted state
+    Status,
+    /// Send a WoL packet to a configured device
+    Wake {
+        /// The `friendly_name` of the device to wake
+        device: String,
+    },
+    /// Resolve the MAC address of a host
+    Resolve {
+        /// An IP address or resolvable hostname
+        host: String,
+    },
+    /// Find online hosts in a subnet and print them as device entries
+    Discover {
+        /// The IPv4 subnet to sweep, in CIDR notation (e.g. 10.0.0.0/24)
+        #[arg(long)]
+        subnet: Subnet,
+        /// Add the discovered hosts to the configuration file, skipping hosts that are already in it
+        #[arg(long)]
+        merge: bool,
+        /// The `name` of the UPS powering the discovered hosts, needed when more than one UPS is configured
+        #[arg(long)]
+        ups: Option<String>,
+    },
+}
@@ -1,86 +1,555 @@
-use crate::mac::{resolve_mac_address, validate_mac_address};
+use crate::mac::{InvalidMacAddress, MacAddress};
+use crate::validation::{ConfigErrors, ConfigProblem, has_errors, validate_config};
 
-use log::{error, info};
+use log::{LevelFilter, info, warn};
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind};
+
+/// Prefix of environment variables that override fields in the configuration file
+static ENV_PREFIX: &str = "NUTJOB_";
+
+/// Suffix of environment variables that point to a file containing the value of a field (e.g. Docker/Kubernetes secrets)
+static ENV_FILE_SUFFIX: &str = "_FILE";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NutjobConfig {
     pub log_level: String,
+    /// Abort if any device is invalid, rather than skipping it
+    #[serde(default)]
+    pub strict: bool,
     pub nut: NutConfig,
+    #[serde(default)]
+    pub ups: Vec<UpsConfig>,
     pub wol: WakeOnLanConfig,
+    #[serde(default)]
+    pub ping: PingConfig,
+    #[serde(default)]
+    pub liveness: LivenessConfig,
+    /// Files that the MAC addresses of devices with `mac_address: dhcp` are looked up in, in order
+    #[serde(default)]
+    pub mac_sources: Vec<MacSourceConfig>,
     pub devices: Vec<DeviceConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NutConfig {
+    /// Deprecated: single UPS configuration, moved into [`NutjobConfig::ups`] during validation
+    pub ups_name: Option<String>,
+    /// Deprecated: see [`NutConfig::ups_name`]
+    pub host: Option<String>,
+    /// Deprecated: see [`NutConfig::ups_name`]
+    pub username: Option<String>,
+    /// Deprecated: see [`NutConfig::ups_name`]
+    pub password: Option<String>,
+    pub polling_interval: u16,
+    /// What is assumed once the NUT server of a UPS has been unreachable for `unreachable_grace_period` seconds
+    #[serde(default)]
+    pub on_unreachable: UnreachablePolicy,
+    /// Also how long a UPS must not be reporting (see [`crate::monitoring::UPSStatusError::UpsUnavailable`]) before it is treated as dead
+    #[serde(default = "default_unreachable_grace_period")]
+    pub unreachable_grace_period: u32,
+}
+
+fn default_unreachable_grace_period() -> u32 {
+    return 120;
+}
+
+/// What is assumed about a UPS whose NUT server can't be reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnreachablePolicy {
+    /// The UPS lost power (e.g. the NUT server is powered by it), so its devices are restored once the NUT server is back
+    #[default]
+    Outage,
+    /// Nothing, the status of the UPS is unknown until the NUT server is back
+    Ignore,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpsConfig {
+    /// Recognizable name that devices use to reference the UPS
+    pub name: String,
     pub ups_name: String,
     pub host: String,
-    pub username: String,
-    pub password: String,
-    pub polling_interval: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The format of a file mapping hosts to MAC addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacSourceFormat {
+    /// dnsmasq lease file (e.g. `/var/lib/misc/dnsmasq.leases`)
+    Dnsmasq,
+    /// ISC dhcpd lease file (e.g. `/var/lib/dhcp/dhcpd.leases`)
+    IscDhcpd,
+    /// Kea memfile lease file (e.g. `/var/lib/kea/kea-leases4.csv`)
+    Kea,
+    /// `/etc/ethers` style file of MAC addresses and hostnames/IP addresses
+    Ethers,
+}
+
+/// A file that MAC addresses are looked up in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacSourceConfig {
+    pub format: MacSourceFormat,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WakeOnLanConfig {
     pub min_battery_percentage: u8,
     pub restore_delay: u16,
     pub restore_timeout: u16,
     pub reattempt_delay: u16,
+    /// Maximum number of devices waking (sent a WoL packet but not yet online) at once, 0 for no limit
+    #[serde(default)]
+    pub max_concurrent_wakes: u16,
+    /// Minimum time in seconds between WoL packets sent to any device
+    #[serde(default)]
+    pub wake_interval: u16,
+    /// Pause waking devices while the load of the UPS is above this percentage
+    pub max_load_percentage: Option<u8>,
+    /// Time in seconds between re-resolving the MAC addresses of online `arp` devices, 0 to only resolve them once
+    #[serde(default = "default_mac_refresh_interval")]
+    pub mac_refresh_interval: u32,
+}
+
+fn default_mac_refresh_interval() -> u32 {
+    return 60 * 60;
+}
+
+/// How devices are pinged to check whether they are online
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PingConfig {
+    /// Time in milliseconds to wait for each echo reply
+    #[serde(default = "default_ping_timeout")]
+    pub timeout: u32,
+    /// Number of echo requests sent to the device
+    #[serde(default = "default_ping_count")]
+    pub count: u8,
+    /// Number of echo replies needed for the device to be considered online
+    #[serde(default = "default_ping_count")]
+    pub required_successes: u8,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        return PingConfig {
+            timeout: default_ping_timeout(),
+            count: default_ping_count(),
+            required_successes: default_ping_count(),
+        };
+    }
+}
+
+fn default_ping_timeout() -> u32 {
+    return 1000;
+}
+
+fn default_ping_count() -> u8 {
+    return 1;
+}
+
+/// How many consecutive checks it takes for a device to switch between online and offline, so that a single dropped check isn't mistaken for the device going down
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LivenessConfig {
+    /// Consecutive successful checks before an offline device is considered online
+    #[serde(default = "default_successes_to_online")]
+    pub successes_to_online: u8,
+    /// Consecutive failed checks before an online device is considered offline
+    #[serde(default = "default_failures_to_offline")]
+    pub failures_to_offline: u8,
+    /// Time in seconds before a UPS switches to battery power during which a device that passed any check counts as online before the shutdown
+    #[serde(default = "default_online_lookback")]
+    pub online_lookback: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        return LivenessConfig {
+            successes_to_online: default_successes_to_online(),
+            failures_to_offline: default_failures_to_offline(),
+            online_lookback: default_online_lookback(),
+        };
+    }
+}
+
+fn default_successes_to_online() -> u8 {
+    return 1;
+}
+
+fn default_failures_to_offline() -> u8 {
+    return 3;
+}
+
+fn default_online_lookback() -> u32 {
+    return 60;
+}
+
+/// Per-device overrides of [`LivenessConfig`], unset fields use the global value
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LivenessOverrides {
+    pub successes_to_online: Option<u8>,
+    pub failures_to_offline: Option<u8>,
+    pub online_lookback: Option<u32>,
+}
+
+impl LivenessConfig {
+    /// `with_overrides()` returns a copy of the configuration with every field set in `overrides` replaced
+    pub fn with_overrides(&self, overrides: &LivenessOverrides) -> LivenessConfig {
+        return LivenessConfig {
+            successes_to_online: overrides
+                .successes_to_online
+                .unwrap_or(self.successes_to_online),
+            failures_to_offline: overrides
+                .failures_to_offline
+                .unwrap_or(self.failures_to_offline),
+            online_lookback: overrides.online_lookback.unwrap_or(self.online_lookback),
+        };
+    }
+}
+
+/// Per-device overrides of [`WakeOnLanConfig`], unset fields use the global value
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WakeOnLanOverrides {
+    pub min_battery_percentage: Option<u8>,
+    pub restore_delay: Option<u16>,
+    pub restore_timeout: Option<u16>,
+    pub reattempt_delay: Option<u16>,
+}
+
+impl WakeOnLanConfig {
+    /// `with_overrides()` returns a copy of the configuration with every field set in `overrides` replaced
+    pub fn with_overrides(&self, overrides: &WakeOnLanOverrides) -> WakeOnLanConfig {
+        return WakeOnLanConfig {
+            min_battery_percentage: overrides
+                .min_battery_percentage
+                .unwrap_or(self.min_battery_percentage),
+            restore_delay: overrides.restore_delay.unwrap_or(self.restore_delay),
+            restore_timeout: overrides.restore_timeout.unwrap_or(self.restore_timeout),
+            reattempt_delay: overrides.reattempt_delay.unwrap_or(self.reattempt_delay),
+            ..self.clone()
+        };
+    }
+}
+
+/// When a device is woken after power is restored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WakePolicy {
+    /// Only if the device was online before its UPS switched to battery power
+    #[default]
+    IfWasOnline,
+    /// Even if the device was offline before its UPS switched to battery power
+    Always,
+    /// Never woken automatically
+    Never,
+}
+
+/// When a device powered by more than one UPS is considered to have lost power
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerPolicy {
+    /// When any of its UPSes lose power
+    Any,
+    /// When all of its UPSes lose power (e.g. servers with redundant power supplies)
+    #[default]
+    All,
+}
+
+/// Where the MAC address of a device comes from, parsed from its `mac_address`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacAddressSource {
+    /// Resolved from the `host` of the device through ARP/NDP, set with `mac_address: arp`
+    Arp,
+    /// Looked up by the `host` of the device in the files in `mac_sources`, set with `mac_address: dhcp`
+    Dhcp,
+    /// Given in the configuration file
+    Static(MacAddress),
+}
+
+impl TryFrom<String> for MacAddressSource {
+    type Error = InvalidMacAddress;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.trim().eq_ignore_ascii_case("arp") {
+            return Ok(MacAddressSource::Arp);
+        }
+
+        if value.trim().eq_ignore_ascii_case("dhcp") {
+            return Ok(MacAddressSource::Dhcp);
+        }
+
+        return value.parse().map(MacAddressSource::Static);
+    }
+}
+
+/// How a device is checked to see if it is online
+///
+/// Checks with a `timeout` (in milliseconds) use `ping.timeout` if it isn't given.
+/// `{host}` in the `url` of HTTP checks and in commands is replaced with the address of the device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthCheckConfig {
+    /// ICMP echo, using the `ping` settings
+    #[default]
+    Icmp,
+    /// Connecting to a TCP port
+    Tcp { port: u16, timeout: Option<u32> },
+    /// An HTTP(S) GET request answered (after following redirects) with the expected status code
+    Http {
+        #[serde(default = "default_http_url")]
+        url: String,
+        #[serde(default = "default_expected_status")]
+        expected_status: u16,
+        /// Whether the certificate of HTTPS servers is verified, disable for self-signed certificates
+        #[serde(default = "default_verify_tls")]
+        verify_tls: bool,
+        timeout: Option<u32>,
+    },
+    /// An SSH server sending its banner
+    Ssh {
+        #[serde(default = "default_ssh_port")]
+        port: u16,
+        timeout: Option<u32>,
+    },
+    /// The device being present in the neighbor (ARP/NDP) table, for devices that drop ICMP
+    Arp,
+    /// A command (run by the shell) exiting with code 0
+    Command {
+        command: String,
+        timeout: Option<u32>,
+    },
+}
+
+fn default_http_url() -> String {
+    return String::from("http://{host}/");
+}
+
+fn default_expected_status() -> u16 {
+    return 200;
+}
+
+fn default_verify_tls() -> bool {
+    return true;
+}
+
+fn default_ssh_port() -> u16 {
+    return 22;
+}
+
+impl fmt::Display for HealthCheckConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Icmp => write!(f, "ping"),
+            Self::Tcp { port, .. } => write!(f, "a TCP connection to port {port}"),
+            Self::Http {
+                url,
+                expected_status,
+                ..
+            } => write!(f, "GET {url} with status {expected_status}"),
+            Self::Ssh { port, .. } => write!(f, "an SSH connection to port {port}"),
+            Self::Arp => write!(f, "ARP/NDP"),
+            Self::Command { command, .. } => write!(f, "the command '{command}'"),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceConfig {
     pub friendly_name: String,
-    pub host: String,
+    /// The IP address or hostname of the device, optional if `mac_address` is given, in which case the current IP address is discovered from the MAC address
+    pub host: Option<String>,
+    /// `arp`, `dhcp` or a MAC address, parsed into `mac_source` while validating the configuration so an invalid one only skips this device
     pub mac_address: String,
+    /// Where the MAC address of the device comes from, `None` until `mac_address` is validated (or if it is invalid)
+    #[serde(skip)]
+    pub mac_source: Option<MacAddressSource>,
+    /// The `name` of the UPS (or list of UPSes) powering the device, optional if only one UPS is configured
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub ups: Vec<String>,
+    #[serde(default)]
+    pub power_policy: PowerPolicy,
+    #[serde(default)]
+    pub wake_policy: WakePolicy,
+    /// How the device is checked to see if it is online, ICMP ping by default
+    #[serde(default)]
+    pub check: HealthCheckConfig,
+    #[serde(default)]
+    pub liveness: LivenessOverrides,
+    #[serde(default)]
+    pub wol: WakeOnLanOverrides,
+    /// The `friendly_name`s of devices that must be online before this device is woken
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    pub depends_on: Vec<String>,
+    /// Time in seconds to wait after this device comes online before waking devices that depend on it
+    #[serde(default)]
+    pub settle_time: u16,
+    /// The MAC address resolved while loading the configuration, for devices with `mac_address: arp` or `dhcp`
+    #[serde(skip)]
+    pub resolved_mac_address: Option<MacAddress>,
 }
 
-/// `get_raw_config()` returns the deserialized, unedited version of the configuration file.
-fn get_raw_config() -> Result<NutjobConfig, config::ConfigError> {
-    let raw_config = config::Config::builder()
-        .add_source(config::File::with_name("/nutjob/config.yaml"))
-        .build()
-        .unwrap();
+impl DeviceConfig {
+    /// `resolves_mac_address()` returns true if the MAC address of the device is resolved at runtime (and cached in the state) rather than given
+    pub fn resolves_mac_address(&self) -> bool {
+        return matches!(
+            self.mac_source,
+            Some(MacAddressSource::Arp | MacAddressSource::Dhcp)
+        );
+    }
 
-    return raw_config.try_deserialize::<NutjobConfig>();
+    /// `known_mac_address()` returns the MAC address of the device, or `None` if it is resolved at runtime and hasn't been resolved yet
+    pub fn known_mac_address(&self) -> Option<MacAddress> {
+        return match self.mac_source {
+            Some(MacAddressSource::Static(mac_address)) => Some(mac_address),
+            _ => self.resolved_mac_address,
+        };
+    }
 }
 
-/// `get_config` returns the validated configuration file, with MAC addresses resolved.
-pub fn get_config() -> NutjobConfig {
-    info!(target: "Config", "Loading configuration file");
-    let raw_config = get_raw_config();
-
-    if raw_config.is_err() {
-        panic!("Unable to load config file! Make sure it is accessible and formatted correctly!");
+/// `deserialize_one_or_many()` deserializes either a single string or a list of strings into a list
+fn deserialize_one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
     }
 
-    let mut config = raw_config.unwrap();
-    info!(target: "Config", "Loaded configuration file successfully");
+    return match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    };
+}
 
-    config.devices.retain_mut(|device| {
-        if device.mac_address == "arp" {
-            let resolved_mac_address = resolve_mac_address(&device.host);
+/// `string_to_level_filter()` converts a log level from the configuration file into a [`LevelFilter`]
+pub fn string_to_level_filter(log_level: &str) -> Result<LevelFilter, Error> {
+    return match log_level.to_lowercase().as_str() {
+        "off" => Ok(LevelFilter::Off),
+        "trace" => Ok(LevelFilter::Trace),
+        "debug" => Ok(LevelFilter::Debug),
+        "info" => Ok(LevelFilter::Info),
+        "warn" => Ok(LevelFilter::Warn),
+        "error" => Ok(LevelFilter::Error),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Invalid log level provided",
+        )),
+    };
+}
 
-            match resolved_mac_address {
-                Ok(mac_address) => {
-                    device.mac_address = mac_address;
-                },
-                Err(e) => {
-                    error!(target: "Config", "Unable to resolve MAC address for '{}': {e}", device.friendly_name);
+/// `env_key_to_path()` converts the name of an environment variable (without [`ENV_PREFIX`]) into a configuration path
+///
+/// Nested fields are separated by `__` and list indexes are given as numbers, for example:
+/// - `UPS__0__PASSWORD` -> `ups[0].password`
+/// - `DEVICES__0__MAC_ADDRESS` -> `devices[0].mac_address`
+fn env_key_to_path(key: &str) -> String {
+    let mut path = String::new();
 
-                    return false;
-                }
+    for segment in key.to_lowercase().split("__") {
+        if segment.parse::<usize>().is_ok() {
+            path.push_str(&format!("[{segment}]"));
+        } else {
+            if !path.is_empty() {
+                path.push('.');
             }
+            path.push_str(segment);
         }
-        
-        let valid_mac_address = validate_mac_address(&device.mac_address);
+    }
 
-        if !valid_mac_address {
-            error!(target: "Config", "Invalid MAC address given for '{}': Make sure the MAC address is formatted correctly.", device.friendly_name);
+    return path;
+}
+
+/// `get_env_overrides()` returns the configuration paths and values set by `NUTJOB_*` environment variables
+///
+/// Variables ending in `_FILE` are read from the file they point to, with trailing newlines removed.
+/// They are returned after the plain variables so that they take precedence when both are set.
+fn get_env_overrides() -> Result<Vec<(String, String)>, config::ConfigError> {
+    let mut overrides: Vec<(String, String)> = Vec::new();
+    let mut file_overrides: Vec<(String, String)> = Vec::new();
+
+    for (name, value) in env::vars() {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        match key.strip_suffix(ENV_FILE_SUFFIX) {
+            Some(key) => {
+                let contents = read_to_string(&value).map_err(|e| {
+                    config::ConfigError::Message(format!("Unable to read {name} ({value}): {e}"))
+                })?;
+
+                file_overrides.push((
+                    env_key_to_path(key),
+                    contents.trim_end_matches(['\r', '\n']).to_string(),
+                ));
+            }
+            None => overrides.push((env_key_to_path(key), value)),
         }
+    }
+
+    overrides.append(&mut file_overrides);
+
+    return Ok(overrides);
+}
+
+/// `get_raw_config()` returns the deserialized, unedited version of the configuration file.
+///
+/// Values are layered in the following order, with later sources taking precedence:
+/// 1. The configuration file at `path`
+/// 2. `NUTJOB_*` environment variables (e.g. `NUTJOB_UPS__0__PASSWORD`)
+/// 3. `NUTJOB_*_FILE` environment variables (e.g. `NUTJOB_UPS__0__PASSWORD_FILE=/run/secrets/nut_password`)
+pub fn get_raw_config(path: &str) -> Result<NutjobConfig, config::ConfigError> {
+    let mut builder = config::Config::builder().add_source(config::File::with_name(path));
+
+    for (key, value) in get_env_overrides()? {
+        builder = builder.set_override(key, value)?;
+    }
+
+    let raw_config = builder.build()?;
 
-        return valid_mac_address;
-    });
+    return raw_config.try_deserialize::<NutjobConfig>();
+}
+
+/// `load_config()` loads and validates the configuration file at `path`, with MAC addresses resolved.
+///
+/// It returns the configuration along with any warnings, or every problem found if the configuration can't be used.
+/// See [`validate_config`] for `check_reachability`.
+pub fn load_config(
+    path: &str,
+    check_reachability: bool,
+) -> Result<(NutjobConfig, Vec<ConfigProblem>), ConfigErrors> {
+    let mut config = get_raw_config(path)?;
+
+    let problems = validate_config(&mut config, check_reachability);
+
+    if has_errors(&problems) {
+        return Err(ConfigErrors { problems });
+    }
+
+    return Ok((config, problems));
+}
+
+/// `get_config` returns the validated configuration file at `path`, with MAC addresses resolved.
+///
+/// Any warnings found while validating are logged.
+pub fn get_config(path: &str) -> Result<NutjobConfig, ConfigErrors> {
+    info!(target: "Config", "Loading configuration file");
+    let (config, warnings) = load_config(path, false)?;
+
+    for warning in warnings {
+        warn!(target: "Config", "{}: {}", warning.field, warning.message);
+    }
+
+    info!(target: "Config", "Loaded configuration file successfully");
 
-    return config;
+    return Ok(config);
 }
@@ -0,0 +1,193 @@
+use crate::config::DeviceConfig;
+
+/// `wake_stages()` groups `devices` into stages, where every device only depends on devices in earlier stages
+///
+/// Dependencies on devices that aren't in `devices` are ignored.
+/// It returns the stages, along with the devices that could not be placed because they are part of (or depend on) a dependency cycle.
+pub fn wake_stages(devices: &[&DeviceConfig]) -> (Vec<Vec<String>>, Vec<String>) {
+    let mut stages: Vec<Vec<String>> = Vec::new();
+    let mut placed: Vec<String> = Vec::new();
+    let mut remaining: Vec<&DeviceConfig> = devices.to_vec();
+
+    loop {
+        let stage: Vec<String> = remaining
+            .iter()
+            .filter(|device| {
+                device.depends_on.iter().all(|dependency| {
+                    placed.contains(dependency)
+                        || !devices
+                            .iter()
+                            .any(|device| device.friendly_name == *dependency)
+                })
+            })
+            .map(|device| device.friendly_name.clone())
+            .collect();
+
+        if stage.is_empty() {
+            break;
+        }
+
+        remaining.retain(|device| !stage.contains(&device.friendly_name));
+        placed.extend(stage.iter().cloned());
+        stages.push(stage);
+    }
+
+    let unplaced = remaining
+        .iter()
+        .map(|device| device.friendly_name.clone())
+        .collect();
+
+    return (stages, unplaced);
+}
+
+/// `find_cycle()` returns the names of the devices in a dependency cycle reachable from `start`, with the first device repeated at the end
+pub fn find_cycle(devices: &[&DeviceConfig], start: &str) -> Option<Vec<String>> {
+    let mut path: Vec<String> = vec![start.to_string()];
+
+    return find_cycle_from(devices, &mut path);
+}
+
+fn find_cycle_from(devices: &[&DeviceConfig], path: &mut Vec<String>) -> Option<Vec<String>> {
+    let current = path.last()?.clone();
+    let device = devices
+        .iter()
+        .find(|device| device.friendly_name == current)?;
+
+    for dependency in &device.depends_on {
+        if let Some(position) = path.iter().position(|name| name == dependency) {
+            let mut cycle = path[position..].to_vec();
+            cycle.push(dependency.clone());
+
+            return Some(cycle);
+        }
+
+        path.push(dependency.clone());
+        if let Some(cycle) = find_cycle_from(devices, path) {
+            return Some(cycle);
+        }
+        path.pop();
+    }
+
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(friendly_name: &str, depends_on: &[&str]) -> DeviceConfig {
+        return DeviceConfig {
+            friendly_name: friendly_name.to_string(),
+            host: None,
+            mac_address: String::from("arp"),
+            mac_source: None,
+            ups: Vec::new(),
+            power_policy: Default::default(),
+            wake_policy: Default::default(),
+            check: Default::default(),
+            liveness: Default::default(),
+            wol: Default::default(),
+            depends_on: depends_on
+                .iter()
+                .map(|dependency| dependency.to_string())
+                .collect(),
+            settle_time: 0,
+            resolved_mac_address: None,
+        };
+    }
+
+    #[test]
+    fn devices_without_dependencies_share_the_first_stage() {
+        let devices = [device("a", &[]), device("b", &[])];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        let (stages, unplaced) = wake_stages(&devices);
+
+        assert_eq!(stages, vec![vec!["a", "b"]]);
+        assert!(unplaced.is_empty());
+    }
+
+    #[test]
+    fn devices_wake_after_their_dependencies() {
+        let devices = [
+            device("app", &["db", "nas"]),
+            device("db", &["nas"]),
+            device("nas", &[]),
+            device("switch", &[]),
+        ];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        let (stages, unplaced) = wake_stages(&devices);
+
+        assert_eq!(stages, vec![vec!["nas", "switch"], vec!["db"], vec!["app"]]);
+        assert!(unplaced.is_empty());
+    }
+
+    #[test]
+    fn dependencies_on_other_devices_are_ignored() {
+        let devices = [device("a", &["elsewhere"]), device("b", &["a"])];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        let (stages, unplaced) = wake_stages(&devices);
+
+        assert_eq!(stages, vec![vec!["a"], vec!["b"]]);
+        assert!(unplaced.is_empty());
+    }
+
+    #[test]
+    fn devices_in_or_depending_on_a_cycle_are_unplaced() {
+        let devices = [
+            device("a", &["b"]),
+            device("b", &["c"]),
+            device("c", &["b"]),
+            device("d", &[]),
+        ];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        let (stages, unplaced) = wake_stages(&devices);
+
+        assert_eq!(stages, vec![vec!["d"]]);
+        assert_eq!(unplaced, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn find_cycle_reports_the_cycle_reachable_from_a_device() {
+        let devices = [
+            device("a", &["b"]),
+            device("b", &["c"]),
+            device("c", &["b"]),
+        ];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        let cycle = find_cycle(&devices, "a").unwrap();
+
+        assert_eq!(cycle.join(" -> "), "b -> c -> b");
+        assert_eq!(
+            find_cycle(&devices, "c").unwrap().join(" -> "),
+            "c -> b -> c"
+        );
+    }
+
+    #[test]
+    fn find_cycle_reports_a_device_depending_on_itself() {
+        let devices = [device("a", &["a"])];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        assert_eq!(
+            find_cycle(&devices, "a"),
+            Some(vec![String::from("a"), String::from("a")])
+        );
+    }
+
+    #[test]
+    fn find_cycle_returns_none_without_a_cycle() {
+        let devices = [
+            device("a", &["b", "c"]),
+            device("b", &["c"]),
+            device("c", &["elsewhere"]),
+        ];
+        let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+        assert_eq!(find_cycle(&devices, "a"), None);
+    }
+}
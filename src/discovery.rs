@@ -0,0 +1,618 @@
+use log::{info, warn};
+use std::fs::{read_to_string, remove_file, rename, write};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::config::{
+    DeviceConfig, HealthCheckConfig, NutjobConfig, PingConfig, get_raw_config, load_config,
+};
+use crate::mac::{MacAddress, resolve_mac_address};
+use crate::monitoring::is_device_online;
+use crate::probing::run_pool;
+use crate::validation::{ConfigProblem, format_problems};
+
+/// Number of hosts pinged at once while sweeping a subnet
+const SWEEP_WORKERS: usize = 64;
+
+/// The smallest prefix length that can be swept, to keep sweeps to at most 65534 hosts
+const MIN_PREFIX_LENGTH: u8 = 16;
+
+/// An IPv4 subnet in CIDR notation, such as `10.0.0.0/24`
+#[derive(Debug, Clone, Copy)]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix_length: u8,
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let (network, prefix_length) = value
+            .split_once('/')
+            .ok_or_else(|| format!("'{value}' is not in CIDR notation (e.g. 10.0.0.0/24)"))?;
+
+        let network: Ipv4Addr = network
+            .parse()
+            .map_err(|_| format!("'{network}' is not an IPv4 address"))?;
+        let prefix_length: u8 = prefix_length
+            .parse()
+            .ok()
+            .filter(|prefix_length| *prefix_length <= 32)
+            .ok_or_else(|| format!("'{prefix_length}' is not a prefix length between 0 and 32"))?;
+
+        if prefix_length < MIN_PREFIX_LENGTH {
+            return Err(format!(
+                "/{prefix_length} is too large to sweep, use a prefix length of at least /{MIN_PREFIX_LENGTH}"
+            ));
+        }
+
+        return Ok(Subnet {
+            network,
+            prefix_length,
+        });
+    }
+}
+
+impl Subnet {
+    /// `hosts()` returns every host address in the subnet, leaving out the network and broadcast addresses of subnets larger than /31
+    fn hosts(&self) -> Vec<Ipv4Addr> {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_length))
+            .unwrap_or(0);
+        let network = u32::from(self.network) & mask;
+        let broadcast = network | !mask;
+
+        if self.prefix_length >= 31 {
+            return (network..=broadcast).map(Ipv4Addr::from).collect();
+        }
+
+        return (network + 1..broadcast).map(Ipv4Addr::from).collect();
+    }
+}
+
+/// A host found while sweeping a subnet
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub friendly_name: String,
+    pub ip: Ipv4Addr,
+    pub hostname: Option<String>,
+    pub mac_address: MacAddress,
+}
+
+/// `reverse_lookup()` returns the hostname of `ip` from reverse DNS, or `None` if it has none
+#[cfg(unix)]
+fn reverse_lookup(ip: Ipv4Addr) -> Option<String> {
+    let mut address: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+    address.sin_family = libc::AF_INET as libc::sa_family_t;
+    address.sin_addr = libc::in_addr {
+        s_addr: u32::from_ne_bytes(ip.octets()),
+    };
+
+    // NI_MAXHOST from <netdb.h>
+    let mut hostname = [0 as libc::c_char; 1025];
+
+    let result = unsafe {
+        libc::getnameinfo(
+            &address as *const libc::sockaddr_in as *const libc::sockaddr,
+            size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            hostname.as_mut_ptr(),
+            hostname.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+
+    if result != 0 {
+        return None;
+    }
+
+    let hostname = unsafe { std::ffi::CStr::from_ptr(hostname.as_ptr()) };
+
+    return hostname.to_str().ok().map(String::from);
+}
+
+/// `reverse_lookup()` returns the hostname of `ip` from reverse DNS, which isn't supported on this platform
+#[cfg(not(unix))]
+fn reverse_lookup(_ip: Ipv4Addr) -> Option<String> {
+    return None;
+}
+
+/// `sweep_subnet()` pings every host in `subnet` (several at a time), returning the hosts that responded
+fn sweep_subnet(subnet: &Subnet, ping_config: &PingConfig) -> Vec<Ipv4Addr> {
+    let hosts = subnet.hosts();
+
+    info!("Sweeping {} host(s)", hosts.len());
+
+    let online = run_pool(&hosts, SWEEP_WORKERS, |ip| {
+        return is_device_online(&ip.to_string(), &HealthCheckConfig::Icmp, ping_config);
+    });
+
+    return hosts
+        .into_iter()
+        .zip(online)
+        .filter(|(_, online)| *online)
+        .map(|(ip, _)| ip)
+        .collect();
+}
+
+/// `unique_name()` returns `name`, or `name` with a numbered suffix if it is already in `taken`
+fn unique_name(name: &str, taken: &[String]) -> String {
+    let mut candidate = name.to_string();
+    let mut suffix = 2;
+
+    while taken.contains(&candidate) {
+        candidate = format!("{name}-{suffix}");
+        suffix += 1;
+    }
+
+    return candidate;
+}
+
+/// `skip_reason()` returns why the host at `ip` with `mac_address` is left out of the discovered devices, or `None` if it is new
+///
+/// Hosts are left out if they are already one of the `existing` devices (by IP address or MAC address), or share their MAC address with a host in `discovered` (such as a host with several IP addresses), which would be a duplicate MAC address
+fn skip_reason(
+    ip: Ipv4Addr,
+    mac_address: MacAddress,
+    existing: &[DeviceConfig],
+    discovered: &[DiscoveredDevice],
+) -> Option<String> {
+    let already_configured = existing.iter().any(|device| {
+        device.known_mac_address() == Some(mac_address)
+            || device
+                .host
+                .as_deref()
+                .is_some_and(|host| host.parse::<IpAddr>() == Ok(IpAddr::V4(ip)))
+    });
+
+    if already_configured {
+        return Some(String::from("already in the configuration file"));
+    }
+
+    return discovered
+        .iter()
+        .find(|device| device.mac_address == mac_address)
+        .map(|device| format!("same MAC address as {}", device.ip));
+}
+
+/// `discover_devices()` sweeps `subnet` for online hosts, returning those whose MAC address could be read from the neighbor table
+///
+/// Hosts already in `existing` (by IP address or MAC address) or sharing the MAC address of another discovered host are left out (see [`skip_reason`]), and new names never clash with existing ones.
+/// Hosts are pinged with `ping_config`.
+pub fn discover_devices(
+    subnet: &Subnet,
+    existing: Option<&NutjobConfig>,
+    ping_config: &PingConfig,
+) -> Vec<DiscoveredDevice> {
+    let mut taken_names: Vec<String> = existing
+        .map(|config| {
+            config
+                .devices
+                .iter()
+                .map(|device| device.friendly_name.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut discovered: Vec<DiscoveredDevice> = Vec::new();
+
+    for ip in sweep_subnet(subnet, ping_config) {
+        let mac_address = match resolve_mac_address(&ip.to_string()) {
+            Ok(mac_address) => mac_address,
+            Err(e) => {
+                warn!("Skipping {ip}: {e}");
+                continue;
+            }
+        };
+
+        let existing_devices = existing.map_or(&[][..], |config| config.devices.as_slice());
+        if let Some(reason) = skip_reason(ip, mac_address, existing_devices, &discovered) {
+            info!("Skipping {ip} ({mac_address}), {reason}");
+            continue;
+        }
+
+        let hostname = reverse_lookup(ip);
+        let name = hostname
+            .as_deref()
+            .and_then(|hostname| hostname.split('.').next())
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .unwrap_or_else(|| ip.to_string());
+
+        let friendly_name = unique_name(&name, &taken_names);
+        taken_names.push(friendly_name.clone());
+
+        discovered.push(DiscoveredDevice {
+            friendly_name,
+            ip,
+            hostname,
+            mac_address,
+        });
+    }
+
+    return discovered;
+}
+
+/// `format_devices()` formats `devices` as entries of the `devices` list of the configuration file, powered by the UPS named `ups` if given, with each line prefixed by `indent`
+pub fn format_devices(devices: &[DiscoveredDevice], ups: Option<&str>, indent: &str) -> String {
+    return devices
+        .iter()
+        .map(|device| {
+            let hostname_comment = device
+                .hostname
+                .as_ref()
+                .map(|hostname| format!(" # {hostname}"))
+                .unwrap_or_default();
+
+            let ups_line = ups
+                .map(|ups| format!("{indent}  ups: \"{ups}\"\n"))
+                .unwrap_or_default();
+
+            return format!(
+                "{indent}- friendly_name: \"{}\"\n{indent}  host: {}{hostname_comment}\n{indent}  mac_address: {}\n{ups_line}",
+                device.friendly_name, device.ip, device.mac_address
+            );
+        })
+        .collect();
+}
+
+/// `merge_devices()` inserts `devices` at the end of the top-level `devices` list in the configuration file `contents`, keeping everything else (including comments) as is
+fn merge_devices(contents: &str, devices: &[DiscoveredDevice], ups: Option<&str>) -> String {
+    let mut lines: Vec<&str> = contents.lines().collect();
+
+    let Some(devices_line) = lines
+        .iter()
+        .position(|line| matches!(line.trim_end(), "devices:" | "devices: []"))
+    else {
+        let separator = if contents.is_empty() || contents.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+
+        return format!(
+            "{contents}{separator}devices:\n{}",
+            format_devices(devices, ups, "  ")
+        );
+    };
+
+    // An empty flow-style list becomes a block list so the entries can follow it
+    lines[devices_line] = "devices:";
+
+    // The list ends at the next top-level key
+    let end = lines[devices_line + 1..]
+        .iter()
+        .position(|line| {
+            !line.is_empty()
+                && !line.starts_with(char::is_whitespace)
+                && !line.starts_with('#')
+                && !line.starts_with('-')
+        })
+        .map_or(lines.len(), |position| devices_line + 1 + position);
+
+    // Insert after the last entry rather than after any blank lines separating the next key
+    let insert_at = lines[devices_line + 1..end]
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(devices_line + 1, |position| devices_line + 2 + position);
+
+    let indent: String = lines[devices_line + 1..end]
+        .iter()
+        .find(|line| line.trim_start().starts_with('-'))
+        .map(|line| line.chars().take_while(|c| c.is_whitespace()).collect())
+        .unwrap_or_else(|| String::from("  "));
+
+    let mut merged: String = lines[..insert_at]
+        .iter()
+        .map(|line| format!("{line}\n"))
+        .collect();
+    merged.push_str(&format_devices(devices, ups, &indent));
+    merged.extend(lines[insert_at..].iter().map(|line| format!("{line}\n")));
+
+    return merged;
+}
+
+/// `merge_into_config()` adds `devices` (powered by the UPS named `ups`, if given) to the configuration file at `config_path`
+///
+/// The merged file is validated before it replaces the configuration file, which is left untouched if any problem is found with the new devices (even one that would only skip them) or the file as a whole.
+pub fn merge_into_config(
+    config_path: &str,
+    devices: &[DiscoveredDevice],
+    ups: Option<&str>,
+) -> Result<()> {
+    let original = read_to_string(config_path)?;
+
+    // Validated in a separate file first, so a running service watching the configuration file never sees an invalid one
+    // The file format is inferred from the extension, so it is kept (e.g. `config.merge.yaml`)
+    let config_file = Path::new(config_path);
+    let merged_path = match config_file.extension() {
+        Some(extension) => {
+            config_file.with_extension(format!("merge.{}", extension.to_string_lossy()))
+        }
+        None => config_file.with_extension("merge"),
+    };
+    let merged_path = merged_path.to_string_lossy().to_string();
+    write(&merged_path, merge_devices(&original, devices, ups))?;
+
+    if let Err(e) = validate_merged_config(&merged_path, devices.len()) {
+        let _ = remove_file(&merged_path);
+        return Err(e);
+    }
+
+    // Renaming fails when the configuration file is a bind mount (such as a single file mounted into a container), in which case it is overwritten instead
+    if rename(&merged_path, config_path).is_err() {
+        let merged = read_to_string(&merged_path)?;
+        let _ = remove_file(&merged_path);
+        write(config_path, merged)?;
+    }
+
+    return Ok(());
+}
+
+/// `validate_merged_config()` checks the merged configuration file at `path`, whose last `added` devices were just discovered
+fn validate_merged_config(path: &str, added: usize) -> Result<()> {
+    let invalid = |problems: String| {
+        return Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "The merged configuration file would be invalid, so it was left unchanged\n{problems}"
+            ),
+        );
+    };
+
+    let device_count = get_raw_config(path)
+        .map_err(|e| invalid(e.to_string()))?
+        .devices
+        .len();
+
+    let (_, problems) = load_config(path, false).map_err(|errors| invalid(errors.to_string()))?;
+
+    // Problems with existing devices were there before the merge, while any problem with a new device would leave it skipped
+    let new_device_fields: Vec<String> = (device_count.saturating_sub(added)..device_count)
+        .map(|index| format!("devices[{index}]"))
+        .collect();
+    let new_device_problems: Vec<ConfigProblem> = problems
+        .into_iter()
+        .filter(|problem| {
+            new_device_fields.iter().any(|field| {
+                problem.field == *field || problem.field.starts_with(&format!("{field}."))
+            })
+        })
+        .collect();
+
+    if !new_device_problems.is_empty() {
+        return Err(invalid(format_problems(&new_device_problems)));
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MacAddressSource;
+
+    fn subnet(value: &str) -> Subnet {
+        return value.parse().unwrap();
+    }
+
+    fn ip(value: &str) -> Ipv4Addr {
+        return value.parse().unwrap();
+    }
+
+    fn mac(value: &str) -> MacAddress {
+        return value.parse().unwrap();
+    }
+
+    fn discovered(friendly_name: &str, ip_address: &str, mac_address: &str) -> DiscoveredDevice {
+        return DiscoveredDevice {
+            friendly_name: friendly_name.to_string(),
+            ip: ip(ip_address),
+            hostname: None,
+            mac_address: mac(mac_address),
+        };
+    }
+
+    fn existing(host: Option<&str>, mac_address: &str) -> DeviceConfig {
+        let mac_source = MacAddressSource::try_from(mac_address.to_string()).ok();
+
+        return DeviceConfig {
+            friendly_name: String::from("existing"),
+            host: host.map(String::from),
+            mac_address: mac_address.to_string(),
+            mac_source,
+            ups: Vec::new(),
+            power_policy: Default::default(),
+            wake_policy: Default::default(),
+            check: Default::default(),
+            liveness: Default::default(),
+            wol: Default::default(),
+            depends_on: Vec::new(),
+            settle_time: 0,
+            resolved_mac_address: None,
+        };
+    }
+
+    #[test]
+    fn hosts_leave_out_network_and_broadcast_addresses() {
+        let hosts = subnet("192.168.1.0/30").hosts();
+
+        assert_eq!(hosts, vec![ip("192.168.1.1"), ip("192.168.1.2")]);
+        assert_eq!(subnet("10.0.0.0/24").hosts().len(), 254);
+        assert_eq!(subnet("10.0.0.0/16").hosts().len(), 65534);
+    }
+
+    #[test]
+    fn hosts_of_a_31_are_both_addresses() {
+        let hosts = subnet("192.168.1.4/31").hosts();
+
+        assert_eq!(hosts, vec![ip("192.168.1.4"), ip("192.168.1.5")]);
+    }
+
+    #[test]
+    fn hosts_of_a_32_is_the_address_itself() {
+        assert_eq!(subnet("192.168.1.7/32").hosts(), vec![ip("192.168.1.7")]);
+    }
+
+    #[test]
+    fn hosts_ignore_host_bits_of_the_network() {
+        assert_eq!(
+            subnet("192.168.1.6/30").hosts(),
+            vec![ip("192.168.1.5"), ip("192.168.1.6")]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_subnets() {
+        for value in [
+            "192.168.1.0",
+            "192.168.1/24",
+            "fd00::/64",
+            "192.168.1.0/33",
+            "192.168.1.0/x",
+            "10.0.0.0/15",
+        ] {
+            assert!(value.parse::<Subnet>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn skips_hosts_already_configured_by_ip_or_mac_address() {
+        let existing = [
+            existing(Some("192.168.1.10"), "arp"),
+            existing(None, "f6:2e:3c:67:f1:74"),
+        ];
+
+        assert!(
+            skip_reason(ip("192.168.1.10"), mac("f6:2e:3c:67:f1:75"), &existing, &[]).is_some()
+        );
+        assert!(
+            skip_reason(ip("192.168.1.11"), mac("f6:2e:3c:67:f1:74"), &existing, &[]).is_some()
+        );
+        assert!(
+            skip_reason(ip("192.168.1.11"), mac("f6:2e:3c:67:f1:75"), &existing, &[]).is_none()
+        );
+    }
+
+    #[test]
+    fn skips_hosts_sharing_the_mac_address_of_a_discovered_host() {
+        let discovered = [discovered("server", "192.168.1.10", "f6:2e:3c:67:f1:74")];
+
+        assert_eq!(
+            skip_reason(
+                ip("192.168.1.11"),
+                mac("f6:2e:3c:67:f1:74"),
+                &[],
+                &discovered
+            ),
+            Some(String::from("same MAC address as 192.168.1.10"))
+        );
+        assert!(
+            skip_reason(
+                ip("192.168.1.11"),
+                mac("f6:2e:3c:67:f1:75"),
+                &[],
+                &discovered
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn unique_name_adds_a_suffix() {
+        let taken = vec![String::from("server"), String::from("server-2")];
+
+        assert_eq!(unique_name("server", &taken), "server-3");
+        assert_eq!(unique_name("nas", &taken), "nas");
+    }
+
+    #[test]
+    fn merge_appends_to_the_devices_list_and_keeps_everything_else() {
+        let contents = "\
+# nutjob configuration
+nut:
+  polling_interval: 5 # seconds
+
+devices:
+  # The NAS
+  - friendly_name: nas
+    mac_address: f6:2e:3c:67:f1:70
+
+ping:
+  timeout: 1000
+";
+        let merged = merge_devices(
+            contents,
+            &[discovered("server", "192.168.1.10", "f6:2e:3c:67:f1:74")],
+            Some("rack-a"),
+        );
+
+        assert_eq!(
+            merged,
+            "\
+# nutjob configuration
+nut:
+  polling_interval: 5 # seconds
+
+devices:
+  # The NAS
+  - friendly_name: nas
+    mac_address: f6:2e:3c:67:f1:70
+  - friendly_name: \"server\"
+    host: 192.168.1.10
+    mac_address: f6:2e:3c:67:f1:74
+    ups: \"rack-a\"
+
+ping:
+  timeout: 1000
+"
+        );
+    }
+
+    #[test]
+    fn merge_follows_the_indentation_of_existing_entries() {
+        let contents = "devices:\n- friendly_name: nas\n  mac_address: f6:2e:3c:67:f1:70\n";
+        let merged = merge_devices(
+            contents,
+            &[discovered("server", "192.168.1.10", "f6:2e:3c:67:f1:74")],
+            None,
+        );
+
+        assert_eq!(
+            merged,
+            "devices:\n- friendly_name: nas\n  mac_address: f6:2e:3c:67:f1:70\n- friendly_name: \"server\"\n  host: 192.168.1.10\n  mac_address: f6:2e:3c:67:f1:74\n"
+        );
+    }
+
+    #[test]
+    fn merge_turns_an_empty_list_into_a_block_list() {
+        let merged = merge_devices(
+            "devices: []\nping:\n  timeout: 1000\n",
+            &[discovered("server", "192.168.1.10", "f6:2e:3c:67:f1:74")],
+            None,
+        );
+
+        assert_eq!(
+            merged,
+            "devices:\n  - friendly_name: \"server\"\n    host: 192.168.1.10\n    mac_address: f6:2e:3c:67:f1:74\nping:\n  timeout: 1000\n"
+        );
+    }
+
+    #[test]
+    fn merge_adds_a_devices_list_if_there_is_none() {
+        let merged = merge_devices(
+            "nut:\n  polling_interval: 5",
+            &[discovered("server", "192.168.1.10", "f6:2e:3c:67:f1:74")],
+            None,
+        );
+
+        assert_eq!(
+            merged,
+            "nut:\n  polling_interval: 5\ndevices:\n  - friendly_name: \"server\"\n    host: 192.168.1.10\n    mac_address: f6:2e:3c:67:f1:74\n"
+        );
+    }
+}
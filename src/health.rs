@@ -0,0 +1,224 @@
+use std::io::{BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use ureq::Agent;
+use ureq::tls::TlsConfig;
+
+use crate::config::{HealthCheckConfig, PingConfig};
+use crate::mac::{resolve_host_ip, resolve_mac_address};
+use crate::ping::ping;
+
+/// Time between checks of whether a [`CommandCheck`] has exited
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A way of checking whether a device is online
+pub trait HealthCheck {
+    /// `is_online()` returns true if the device at `address` (an IP address or hostname) passes the check
+    ///
+    /// It returns an error if the check couldn't be carried out at all, rather than the device failing it.
+    fn is_online(&self, address: &str) -> std::io::Result<bool>;
+}
+
+/// Online if enough ICMP echo requests are answered
+pub struct IcmpCheck {
+    pub ping_config: PingConfig,
+}
+
+impl HealthCheck for IcmpCheck {
+    fn is_online(&self, address: &str) -> std::io::Result<bool> {
+        let Ok(ip) = resolve_host_ip(address) else {
+            return Ok(false);
+        };
+
+        return ping(ip, &self.ping_config);
+    }
+}
+
+/// `connect()` opens a TCP connection to `port` on `address`, or returns `None` if it can't be resolved or connected to within `timeout`
+fn connect(address: &str, port: u16, timeout: Duration) -> Option<TcpStream> {
+    let ip = resolve_host_ip(address).ok()?;
+
+    return TcpStream::connect_timeout(&SocketAddr::new(ip, port), timeout).ok();
+}
+
+/// Online if a TCP connection to `port` is accepted
+pub struct TcpCheck {
+    pub port: u16,
+    pub timeout: Duration,
+}
+
+impl HealthCheck for TcpCheck {
+    fn is_online(&self, address: &str) -> std::io::Result<bool> {
+        return Ok(connect(address, self.port, self.timeout).is_some());
+    }
+}
+
+/// `url_host()` formats `address` for use in a URL, putting IPv6 addresses in brackets
+fn url_host(address: &str) -> String {
+    return match address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]"),
+        _ => address.to_string(),
+    };
+}
+
+/// Online if a GET request to `url` is answered with `expected_status`
+pub struct HttpCheck {
+    pub url: String,
+    pub expected_status: u16,
+    pub verify_tls: bool,
+    pub timeout: Duration,
+}
+
+impl HealthCheck for HttpCheck {
+    fn is_online(&self, address: &str) -> std::io::Result<bool> {
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(self.timeout))
+            .http_status_as_error(false)
+            .tls_config(
+                TlsConfig::builder()
+                    .disable_verification(!self.verify_tls)
+                    .build(),
+            )
+            .build()
+            .into();
+
+        let url = self.url.replace("{host}", &url_host(address));
+
+        return match agent.get(&url).call() {
+            Ok(response) => Ok(response.status().as_u16() == self.expected_status),
+            Err(e) => {
+                debug!("GET {url} failed: {e}");
+                Ok(false)
+            }
+        };
+    }
+}
+
+/// Online if an SSH server on `port` sends its banner (`SSH-2.0-...`)
+pub struct SshCheck {
+    pub port: u16,
+    pub timeout: Duration,
+}
+
+impl HealthCheck for SshCheck {
+    fn is_online(&self, address: &str) -> std::io::Result<bool> {
+        let Some(stream) = connect(address, self.port, self.timeout) else {
+            return Ok(false);
+        };
+
+        if stream.set_read_timeout(Some(self.timeout)).is_err() {
+            return Ok(false);
+        }
+
+        // Servers may send other lines before the banner, but the banner must come within the first few
+        let mut lines = BufReader::new(stream).lines();
+        for _ in 0..5 {
+            match lines.next() {
+                Some(Ok(line)) if line.starts_with("SSH-") => return Ok(true),
+                Some(Ok(_)) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        return Ok(false);
+    }
+}
+
+/// Online if the device is in the neighbor (ARP/NDP) table with a resolved MAC address
+///
+/// The device is sent a UDP datagram first, so the entry of a device that went offline fails within a few seconds.
+pub struct ArpCheck;
+
+impl HealthCheck for ArpCheck {
+    fn is_online(&self, address: &str) -> std::io::Result<bool> {
+        return Ok(resolve_mac_address(address).is_ok());
+    }
+}
+
+/// Online if `command` exits with code 0 within `timeout`, killing it otherwise
+pub struct CommandCheck {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl HealthCheck for CommandCheck {
+    fn is_online(&self, address: &str) -> std::io::Result<bool> {
+        let command = self.command.replace("{host}", address);
+
+        #[cfg(target_os = "windows")]
+        let mut child = Command::new("cmd")
+            .arg("/C")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        #[cfg(not(target_os = "windows"))]
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success());
+            }
+
+            if Instant::now() >= deadline {
+                debug!("'{command}' timed out, killing it");
+                let _ = child.kill();
+                let _ = child.wait();
+
+                return Ok(false);
+            }
+
+            sleep(COMMAND_POLL_INTERVAL);
+        }
+    }
+}
+
+/// `health_check()` creates the [`HealthCheck`] described by `config`, with checks that don't set a `timeout` using the one in `ping_config`
+pub fn health_check(config: &HealthCheckConfig, ping_config: &PingConfig) -> Box<dyn HealthCheck> {
+    let timeout_or_default = |timeout: &Option<u32>| {
+        return Duration::from_millis(timeout.unwrap_or(ping_config.timeout).into());
+    };
+
+    return match config {
+        HealthCheckConfig::Icmp => Box::new(IcmpCheck {
+            ping_config: *ping_config,
+        }),
+        HealthCheckConfig::Tcp { port, timeout } => Box::new(TcpCheck {
+            port: *port,
+            timeout: timeout_or_default(timeout),
+        }),
+        HealthCheckConfig::Http {
+            url,
+            expected_status,
+            verify_tls,
+            timeout,
+        } => Box::new(HttpCheck {
+            url: url.clone(),
+            expected_status: *expected_status,
+            verify_tls: *verify_tls,
+            timeout: timeout_or_default(timeout),
+        }),
+        HealthCheckConfig::Ssh { port, timeout } => Box::new(SshCheck {
+            port: *port,
+            timeout: timeout_or_default(timeout),
+        }),
+        HealthCheckConfig::Arp => Box::new(ArpCheck),
+        HealthCheckConfig::Command { command, timeout } => Box::new(CommandCheck {
+            command: command.clone(),
+            timeout: timeout_or_default(timeout),
+        }),
+    };
+}
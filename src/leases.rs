@@ -0,0 +1,405 @@
+use log::debug;
+use std::fs::read_to_string;
+use std::io::Result;
+use std::net::{IpAddr, ToSocketAddrs};
+
+use crate::config::{MacSourceConfig, MacSourceFormat};
+use crate::mac::MacAddress;
+
+/// A MAC address found in a lease file or `/etc/ethers`, along with the host it belongs to
+#[derive(Debug, Clone)]
+pub struct LeaseEntry {
+    pub mac_address: MacAddress,
+    pub ip: Option<IpAddr>,
+    pub hostname: Option<String>,
+}
+
+/// `parse_dnsmasq()` parses a dnsmasq lease file
+///
+/// Each line looks like `<expiry> <mac> <ip> <hostname or *> <client id>`.
+/// DHCPv6 leases (and the `duid` line) have an IAID instead of a MAC address and are skipped.
+fn parse_dnsmasq(contents: &str) -> Vec<LeaseEntry> {
+    return contents
+        .lines()
+        .filter_map(|line| {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() < 4 {
+                return None;
+            }
+
+            return Some(LeaseEntry {
+                mac_address: columns[1].parse().ok()?,
+                ip: columns[2].parse().ok(),
+                hostname: Some(columns[3])
+                    .filter(|hostname| *hostname != "*")
+                    .map(String::from),
+            });
+        })
+        .collect();
+}
+
+/// `parse_isc_dhcpd()` parses an ISC dhcpd lease file, made up of blocks such as:
+///
+/// ```text
+/// lease 192.168.1.10 {
+///   hardware ethernet f6:2e:3c:67:f1:74;
+///   client-hostname "server";
+/// }
+/// ```
+fn parse_isc_dhcpd(contents: &str) -> Vec<LeaseEntry> {
+    let mut entries: Vec<LeaseEntry> = Vec::new();
+
+    let mut in_lease = false;
+    let mut ip: Option<IpAddr> = None;
+    let mut mac_address: Option<MacAddress> = None;
+    let mut hostname: Option<String> = None;
+
+    for line in contents.lines() {
+        let line = line.trim().trim_end_matches(';');
+
+        if let Some(lease) = line.strip_prefix("lease ") {
+            in_lease = true;
+            ip = lease.trim_end_matches('{').trim().parse().ok();
+            mac_address = None;
+            hostname = None;
+        } else if !in_lease {
+            // Anything outside of a lease block (such as a malformed one) is ignored
+            continue;
+        } else if let Some(hardware) = line.strip_prefix("hardware ethernet ") {
+            mac_address = hardware.parse().ok();
+        } else if let Some(client_hostname) = line.strip_prefix("client-hostname ") {
+            hostname = Some(client_hostname.trim_matches('"').to_string());
+        } else if line == "}" {
+            in_lease = false;
+
+            if let Some(mac_address) = mac_address.take() {
+                entries.push(LeaseEntry {
+                    mac_address,
+                    ip: ip.take(),
+                    hostname: hostname.take(),
+                });
+            }
+        }
+    }
+
+    return entries;
+}
+
+/// `parse_kea()` parses a Kea memfile lease file, a CSV file with a header row naming the `address`, `hwaddr` and `hostname` columns
+fn parse_kea(contents: &str) -> Vec<LeaseEntry> {
+    let mut lines = contents.lines();
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(',').collect();
+
+    let column = |name: &str| header.iter().position(|column| column.trim() == name);
+    let (Some(address_column), Some(hwaddr_column)) = (column("address"), column("hwaddr")) else {
+        return Vec::new();
+    };
+    let hostname_column = column("hostname");
+
+    return lines
+        .filter_map(|line| {
+            let columns: Vec<&str> = line.split(',').collect();
+            let mac_address = columns.get(hwaddr_column)?.parse().ok()?;
+
+            return Some(LeaseEntry {
+                mac_address,
+                ip: columns.get(address_column).and_then(|ip| ip.parse().ok()),
+                hostname: hostname_column
+                    .and_then(|hostname_column| columns.get(hostname_column))
+                    .map(|hostname| hostname.trim().trim_end_matches('.'))
+                    .filter(|hostname| !hostname.is_empty())
+                    .map(String::from),
+            });
+        })
+        .collect();
+}
+
+/// `parse_ethers()` parses an `/etc/ethers` style file, where each line is a MAC address followed by a hostname or IP address
+fn parse_ethers(contents: &str) -> Vec<LeaseEntry> {
+    return contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or_default();
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            if columns.len() < 2 {
+                return None;
+            }
+
+            let mac_address = columns[0].parse().ok()?;
+
+            return Some(match columns[1].parse::<IpAddr>() {
+                Ok(ip) => LeaseEntry {
+                    mac_address,
+                    ip: Some(ip),
+                    hostname: None,
+                },
+                Err(_) => LeaseEntry {
+                    mac_address,
+                    ip: None,
+                    hostname: Some(columns[1].to_string()),
+                },
+            });
+        })
+        .collect();
+}
+
+/// `read_mac_source()` reads every entry in the file described by `source`
+pub fn read_mac_source(source: &MacSourceConfig) -> Result<Vec<LeaseEntry>> {
+    let contents = read_to_string(&source.path)?;
+
+    return Ok(match source.format {
+        MacSourceFormat::Dnsmasq => parse_dnsmasq(&contents),
+        MacSourceFormat::IscDhcpd => parse_isc_dhcpd(&contents),
+        MacSourceFormat::Kea => parse_kea(&contents),
+        MacSourceFormat::Ethers => parse_ethers(&contents),
+    });
+}
+
+/// `matches_host()` returns true if `entry` belongs to `host`, by IP address or by hostname (ignoring the domain)
+fn matches_host(entry: &LeaseEntry, host: &str, host_ips: &[IpAddr]) -> bool {
+    if entry.ip.is_some_and(|ip| host_ips.contains(&ip)) {
+        return true;
+    }
+
+    if host.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+
+    let short_name = |name: &str| name.split('.').next().unwrap_or_default().to_lowercase();
+
+    return entry.hostname.as_deref().is_some_and(|hostname| {
+        hostname.eq_ignore_ascii_case(host) || short_name(hostname) == short_name(host)
+    });
+}
+
+/// `find_leased_mac_address()` looks up the MAC address of `host` (an IP address or hostname) in each of the `sources` in order
+///
+/// The last matching entry of a file wins, since lease files are appended to as leases are renewed.
+/// Files that can't be read are skipped.
+pub fn find_leased_mac_address(host: &str, sources: &[MacSourceConfig]) -> Option<MacAddress> {
+    let host_ips: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => (host, 0)
+            .to_socket_addrs()
+            .map(|addresses| addresses.map(|address| address.ip()).collect())
+            .unwrap_or_default(),
+    };
+
+    for source in sources {
+        let entries = match read_mac_source(source) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Unable to read MAC source '{}': {e}", source.path);
+                continue;
+            }
+        };
+
+        if let Some(entry) = entries
+            .iter()
+            .rev()
+            .find(|entry| matches_host(entry, host, &host_ips))
+        {
+            return Some(entry.mac_address);
+        }
+    }
+
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    fn mac(value: &str) -> MacAddress {
+        return value.parse().unwrap();
+    }
+
+    /// `find_in_file()` looks up `host` in a temporary `format` file holding `contents`
+    fn find_in_file(
+        name: &str,
+        format: MacSourceFormat,
+        contents: &str,
+        host: &str,
+    ) -> Option<MacAddress> {
+        let path = std::env::temp_dir().join(format!("nutjob-{}-{name}", std::process::id()));
+        write(&path, contents).unwrap();
+
+        let source = MacSourceConfig {
+            format,
+            path: path.to_string_lossy().to_string(),
+        };
+        let mac_address = find_leased_mac_address(host, &[source]);
+
+        remove_file(&path).unwrap();
+        return mac_address;
+    }
+
+    #[test]
+    fn dnsmasq_reads_ip_and_hostname() {
+        let entries = parse_dnsmasq(
+            "1767225600 f6:2e:3c:67:f1:74 192.168.1.10 server 01:f6:2e:3c:67:f1:74\n\
+             1767225600 f6:2e:3c:67:f1:75 192.168.1.11 * *\n",
+        );
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].mac_address, mac("f6:2e:3c:67:f1:74"));
+        assert_eq!(entries[0].ip, "192.168.1.10".parse().ok());
+        assert_eq!(entries[0].hostname.as_deref(), Some("server"));
+        assert_eq!(entries[1].hostname, None);
+    }
+
+    #[test]
+    fn dnsmasq_skips_dhcpv6_and_malformed_lines() {
+        let entries = parse_dnsmasq(
+            "duid 00:01:00:01:2b:3c:4d:5e:f6:2e:3c:67:f1:74\n\
+             1767225600 1234567 fd00::10 server 00:01:00:01:2b:3c\n\
+             1767225600 f6:2e:3c:67:f1:74\n\
+             \n\
+             not a lease at all\n\
+             1767225600 f6:2e:3c:67:f1:76 192.168.1.12 laptop *\n",
+        );
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].mac_address, mac("f6:2e:3c:67:f1:76"));
+    }
+
+    #[test]
+    fn isc_dhcpd_reads_lease_blocks() {
+        let entries = parse_isc_dhcpd(
+            "# The format of this file is documented in the dhcpd.leases(5) manual page.\n\
+             lease 192.168.1.10 {\n\
+             \x20 starts 4 2026/01/01 00:00:00;\n\
+             \x20 binding state active;\n\
+             \x20 hardware ethernet f6:2e:3c:67:f1:74;\n\
+             \x20 client-hostname \"server\";\n\
+             }\n",
+        );
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].mac_address, mac("f6:2e:3c:67:f1:74"));
+        assert_eq!(entries[0].ip, "192.168.1.10".parse().ok());
+        assert_eq!(entries[0].hostname.as_deref(), Some("server"));
+    }
+
+    #[test]
+    fn isc_dhcpd_skips_blocks_without_a_valid_mac_address() {
+        let entries = parse_isc_dhcpd(
+            "lease 192.168.1.10 {\n\
+             \x20 binding state abandoned;\n\
+             }\n\
+             lease 192.168.1.11 {\n\
+             \x20 hardware ethernet not-a-mac;\n\
+             }\n\
+             hardware ethernet f6:2e:3c:67:f1:74;\n\
+             }\n\
+             lease 192.168.1.12 {\n\
+             \x20 hardware ethernet f6:2e:3c:67:f1:76;\n\
+             }\n",
+        );
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].mac_address, mac("f6:2e:3c:67:f1:76"));
+        assert_eq!(entries[0].ip, "192.168.1.12".parse().ok());
+    }
+
+    #[test]
+    fn isc_dhcpd_block_does_not_inherit_the_previous_hostname() {
+        let entries = parse_isc_dhcpd(
+            "lease 192.168.1.10 {\n\
+             \x20 hardware ethernet f6:2e:3c:67:f1:74;\n\
+             \x20 client-hostname \"server\";\n\
+             }\n\
+             lease 192.168.1.11 {\n\
+             \x20 hardware ethernet f6:2e:3c:67:f1:75;\n\
+             }\n",
+        );
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].hostname, None);
+    }
+
+    #[test]
+    fn isc_dhcpd_superseded_lease_loses_to_the_later_one() {
+        let contents = "lease 192.168.1.10 {\n\
+                        \x20 binding state free;\n\
+                        \x20 hardware ethernet f6:2e:3c:67:f1:74;\n\
+                        \x20 client-hostname \"old\";\n\
+                        }\n\
+                        lease 192.168.1.10 {\n\
+                        \x20 binding state active;\n\
+                        \x20 hardware ethernet f6:2e:3c:67:f1:75;\n\
+                        \x20 client-hostname \"new\";\n\
+                        }\n";
+
+        assert_eq!(
+            find_in_file(
+                "superseded",
+                MacSourceFormat::IscDhcpd,
+                contents,
+                "192.168.1.10"
+            ),
+            Some(mac("f6:2e:3c:67:f1:75"))
+        );
+        // An expired lease still tells the MAC address of the device it was leased to, such as a device that stayed off after an outage
+        assert_eq!(
+            find_in_file("expired", MacSourceFormat::IscDhcpd, contents, "old"),
+            Some(mac("f6:2e:3c:67:f1:74"))
+        );
+    }
+
+    #[test]
+    fn kea_reads_columns_by_header() {
+        let entries = parse_kea(
+            "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state\n\
+             192.168.1.10,f6:2e:3c:67:f1:74,,3600,1767225600,1,0,0,server.lan.,0\n\
+             192.168.1.11,,,3600,1767225600,1,0,0,,0\n",
+        );
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].hostname.as_deref(), Some("server.lan"));
+    }
+
+    #[test]
+    fn kea_without_the_needed_columns_has_no_entries() {
+        assert!(parse_kea("address,client_id\n192.168.1.10,01\n").is_empty());
+        assert!(parse_kea("").is_empty());
+    }
+
+    #[test]
+    fn ethers_reads_hostnames_and_ip_addresses() {
+        let entries = parse_ethers(
+            "# comment\n\
+             f6:2e:3c:67:f1:74 server # trailing comment\n\
+             f6:2e:3c:67:f1:75 192.168.1.11\n\
+             f6:2e:3c:67:f1:76\n\
+             bogus nas\n",
+        );
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].hostname.as_deref(), Some("server"));
+        assert_eq!(entries[1].ip, "192.168.1.11".parse().ok());
+    }
+
+    #[test]
+    fn matches_host_ignores_the_domain_of_hostnames() {
+        let entry = LeaseEntry {
+            mac_address: mac("f6:2e:3c:67:f1:74"),
+            ip: "192.168.1.10".parse().ok(),
+            hostname: Some(String::from("Server.lan")),
+        };
+
+        assert!(matches_host(&entry, "server", &[]));
+        assert!(matches_host(&entry, "server.home", &[]));
+        assert!(matches_host(
+            &entry,
+            "192.168.1.10",
+            &["192.168.1.10".parse().unwrap()]
+        ));
+        assert!(!matches_host(
+            &entry,
+            "192.168.1.11",
+            &["192.168.1.11".parse().unwrap()]
+        ));
+    }
+}
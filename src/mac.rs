@@ -1,85 +1,464 @@
-use regex::Regex;
-use std::io::{Error, ErrorKind};
+use bincode::{Decode, Encode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Error;
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
 #[cfg(target_os = "windows")]
 use std::os::windows::process::CommandExt;
+#[cfg(not(target_os = "linux"))]
 use std::process::{Command, Stdio};
+use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use std::thread::sleep;
+use std::thread::{JoinHandle, spawn};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
 
-/// The `validate_mac_address` function takes in a string and makes sure that it is structured like a MAC address
-///
-/// It returns a bool value depending upon if it matches the regex pattern
-///
-/// Allowed formats:
-/// - f6:2e:3c:67:f1:74
-/// - F6:2E:3C:67:F1:74
-/// - f6-2e-3c-67-f1-74
-/// - F6-2E-3C-67-F1-74
-pub fn validate_mac_address(mac: &str) -> bool {
-    let mac_pattern: Regex = Regex::new(r"^([0-9A-Fa-f]{2}[:\-]){5}([0-9A-Fa-f]{2})$").unwrap();
+use crate::config::{DeviceConfig, MacAddressSource, MacSourceConfig};
+use crate::leases::{find_leased_mac_address, read_mac_source};
+#[cfg(target_os = "linux")]
+use crate::neighbor::{find_neighbor, read_neighbor_table};
+
+/// How many times the neighbor table is checked while waiting for the host to answer ARP/NDP
+#[cfg(target_os = "linux")]
+const LOOKUP_ATTEMPTS: u32 = 10;
+
+/// How long to wait between checks of the neighbor table
+#[cfg(target_os = "linux")]
+const LOOKUP_INTERVAL: Duration = Duration::from_millis(200);
 
-    return mac_pattern.is_match(mac);
+/// The reasons the MAC address of a host could not be resolved
+#[derive(Debug)]
+pub enum MacResolutionError {
+    /// The host has no entry in the neighbor table
+    NotInTable(IpAddr),
+    /// The host has an entry in the neighbor table, but it never answered ARP/NDP
+    Incomplete(IpAddr),
+    /// The host is not in any of the `mac_sources`
+    NotInLeases(String),
+    /// The host or its neighbor table entry could not be looked up at all
+    ResolutionFailed(String),
 }
 
-/// The `resolve_mac_address` function takes in a host string (either an IPv4 address or a resolvable hostname such as `"server.local"`)
-///
-/// It attempts to return the MAC address as the `Ok` value in the `Result`, otherwise it will return an `Error` with a message
-pub fn resolve_mac_address(host: &str) -> Result<String, Error> {
-    // Run ping command to cache the host/IP
-    // ARP may be unable to lookup the MAC address if this is not done
-    #[cfg(target_os = "windows")]
-    let ping_output = Command::new("cmd")
-        .arg("/C")
-        .arg(format!("ping -n 1 {host}"))
-        .output();
+impl fmt::Display for MacResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            MacResolutionError::NotInTable(ip) => {
+                write!(
+                    f,
+                    "{ip} is not in the neighbor table (is it on the local network?)"
+                )
+            }
+            MacResolutionError::Incomplete(ip) => {
+                write!(f, "{ip} did not answer ARP/NDP requests (is it online?)")
+            }
+            MacResolutionError::NotInLeases(host) => {
+                write!(f, "'{host}' was not found in any of the MAC sources")
+            }
+            MacResolutionError::ResolutionFailed(reason) => write!(f, "{reason}"),
+        };
+    }
+}
 
-    #[cfg(not(target_os = "windows"))]
-    let ping_output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ping -c 1 {host}"))
-        .output();
-    if ping_output.is_err() {
-        return Err(ping_output.unwrap_err());
+impl std::error::Error for MacResolutionError {}
+
+impl From<Error> for MacResolutionError {
+    fn from(e: Error) -> Self {
+        return MacResolutionError::ResolutionFailed(e.to_string());
+    }
+}
+
+/// A MAC address, displayed in the canonical lowercase, colon separated form (e.g. `f6:2e:3c:67:f1:74`)
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress([u8; 6]);
+
+/// The error returned when a string is not a MAC address in any of the supported formats
+#[derive(Debug)]
+pub struct InvalidMacAddress(String);
+
+impl fmt::Display for InvalidMacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' is not a valid MAC address (expected a format such as f6:2e:3c:67:f1:74, f6-2e-3c-67-f1-74, f62e.3c67.f174 or f62e3c67f174)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidMacAddress {}
+
+impl MacAddress {
+    /// `MacAddress::from_octets()` creates a MAC address from a hardware address, returning `None` unless it is exactly 6 octets long
+    pub fn from_octets(octets: &[u8]) -> Option<Self> {
+        return <[u8; 6]>::try_from(octets).ok().map(MacAddress);
+    }
+
+    /// `octets()` returns the 6 octets of the MAC address
+    pub fn octets(&self) -> [u8; 6] {
+        return self.0;
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = InvalidMacAddress;
+
+    /// `MacAddress::from_str()` parses a MAC address in any case, surrounded by whitespace, and in any of these formats:
+    /// - f6:2e:3c:67:f1:74 (or with single digit octets, such as `0:1b:...` printed by BSD `arp`)
+    /// - f6-2e-3c-67-f1-74
+    /// - f6 2e 3c 67 f1 74
+    /// - f62e.3c67.f174 (Cisco)
+    /// - f62e3c67f174
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidMacAddress(value.to_string());
+
+        let groups: Vec<&str> = value
+            .split(|c: char| c == ':' || c == '-' || c == '.' || c.is_whitespace())
+            .filter(|group| !group.is_empty())
+            .collect();
+
+        if groups
+            .iter()
+            .any(|group| !group.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(invalid());
+        }
+
+        let hex: String = match groups.len() {
+            6 if groups.iter().all(|group| group.len() <= 2) => {
+                groups.iter().map(|group| format!("{group:0>2}")).collect()
+            }
+            3 if groups.iter().all(|group| group.len() == 4) => groups.concat(),
+            1 if groups[0].len() == 12 => groups[0].to_string(),
+            _ => return Err(invalid()),
+        };
+
+        let mut octets = [0u8; 6];
+        for (index, octet) in octets.iter_mut().enumerate() {
+            *octet =
+                u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        return Ok(MacAddress(octets));
     }
+}
 
-    let ping_failed = ping_output.unwrap().status.code() != Some(0);
+impl TryFrom<String> for MacAddress {
+    type Error = InvalidMacAddress;
 
-    if ping_failed {
-        return Err(Error::new(
-            ErrorKind::HostUnreachable,
-            format!("Failed to ping {host}"),
-        ));
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        return value.parse();
     }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac_address: MacAddress) -> Self {
+        return mac_address.to_string();
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// `resolve_host_ip()` resolves `host` (an IP address or hostname) to an IP address, preferring IPv4
+pub fn resolve_host_ip(host: &str) -> Result<IpAddr, MacResolutionError> {
+    let addresses: Vec<IpAddr> = (host, 0)
+        .to_socket_addrs()
+        .map_err(|e| {
+            MacResolutionError::ResolutionFailed(format!("Unable to resolve '{host}': {e}"))
+        })?
+        .map(|address| address.ip())
+        .collect();
+
+    return addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or(addresses.first())
+        .copied()
+        .ok_or_else(|| {
+            MacResolutionError::ResolutionFailed(format!("'{host}' has no IP addresses"))
+        });
+}
+
+/// `prime_neighbor_entry()` sends a single UDP datagram to the discard port of `ip`, making the kernel resolve its MAC address through ARP/NDP
+fn prime_neighbor_entry(ip: IpAddr) {
+    let bind_address = match ip {
+        IpAddr::V4(_) => "0.0.0.0:0",
+        IpAddr::V6(_) => "[::]:0",
+    };
+
+    if let Ok(socket) = UdpSocket::bind(bind_address) {
+        let _ = socket.send_to(&[0], (ip, 9));
+    }
+}
+
+/// The `resolve_mac_address` function takes in a host string (either an IP address or a resolvable hostname such as `"server.local"`)
+///
+/// It returns the MAC address of the host, read from the kernel's neighbor table on Linux
+#[cfg(target_os = "linux")]
+pub fn resolve_mac_address(host: &str) -> Result<MacAddress, MacResolutionError> {
+    let ip = resolve_host_ip(host)?;
+
+    // The kernel only learns the MAC address once something is sent to the host
+    prime_neighbor_entry(ip);
+
+    let mut error = MacResolutionError::NotInTable(ip);
+    for attempt in 0..LOOKUP_ATTEMPTS {
+        if attempt > 0 {
+            sleep(LOOKUP_INTERVAL);
+        }
+
+        error = match find_neighbor(ip)? {
+            Some(entry) => match entry.mac_address {
+                Some(mac_address) => return Ok(mac_address),
+                None => MacResolutionError::Incomplete(ip),
+            },
+            None => MacResolutionError::NotInTable(ip),
+        };
+    }
+
+    return Err(error);
+}
+
+/// The `resolve_mac_address` function takes in a host string (either an IP address or a resolvable hostname such as `"server.local"`)
+///
+/// It returns the MAC address of the host, read from the output of `arp`
+#[cfg(not(target_os = "linux"))]
+pub fn resolve_mac_address(host: &str) -> Result<MacAddress, MacResolutionError> {
+    let ip = resolve_host_ip(host)?;
+
+    // ARP may be unable to lookup the MAC address if nothing was sent to the host
+    prime_neighbor_entry(ip);
 
-    // Lookup MAC address
     #[cfg(target_os = "windows")]
     let arp_output = Command::new("cmd")
         .stdout(Stdio::piped())
         .arg("/C")
-        .raw_arg(format!(
-            r#"for /f "tokens=2" %a in ('arp -a ^| findstr {host}') do @echo %a"#
-        ))
-        .output();
+        .raw_arg(format!("arp -a {ip}"))
+        .output()?;
 
     #[cfg(not(target_os = "windows"))]
-    let arp_output = Command::new("sh")
+    let arp_output = Command::new("arp")
         .stdout(Stdio::piped())
-        .arg("-c")
-        .arg(format!("arp -n {host}"))
-        .arg("| awk '/^[0-9]/ { print $3 }'")
-        .output();
+        .arg("-n")
+        .arg(ip.to_string())
+        .output()?;
 
-    if arp_output.is_err() {
-        return Err(arp_output.unwrap_err());
+    if arp_output.status.code() != Some(0) {
+        return Err(MacResolutionError::NotInTable(ip));
     }
 
-    let arp_unwraped = arp_output.unwrap();
-    let arp_failed = arp_unwraped.status.code() != Some(0);
+    let stdout = String::from_utf8_lossy(&arp_output.stdout);
+
+    return match stdout
+        .split_whitespace()
+        .find_map(|token| token.parse::<MacAddress>().ok())
+    {
+        Some(mac_address) => Ok(mac_address),
+        None if stdout.contains("incomplete") => Err(MacResolutionError::Incomplete(ip)),
+        None => Err(MacResolutionError::NotInTable(ip)),
+    };
+}
+
+/// `resolve_device_mac_address()` resolves the MAC address of `host` from `source`, looking up `mac_address: dhcp` devices in `mac_sources`
+pub fn resolve_device_mac_address(
+    source: MacAddressSource,
+    host: &str,
+    mac_sources: &[MacSourceConfig],
+) -> Result<MacAddress, MacResolutionError> {
+    return match source {
+        MacAddressSource::Arp => resolve_mac_address(host),
+        MacAddressSource::Dhcp => find_leased_mac_address(host, mac_sources)
+            .ok_or_else(|| MacResolutionError::NotInLeases(host.to_string())),
+        MacAddressSource::Static(mac_address) => Ok(mac_address),
+    };
+}
 
-    if arp_failed {
-        return Err(Error::new(
-            ErrorKind::HostUnreachable,
-            String::from_utf8(arp_unwraped.stderr).unwrap(),
-        ));
+/// `discover_ip_address()` finds the current IP address of the device with `mac_address`, from the neighbor table (on Linux) or else the `mac_sources`
+///
+/// IPv4 addresses are preferred, and the most recent lease wins when the device has several.
+pub fn discover_ip_address(
+    mac_address: MacAddress,
+    mac_sources: &[MacSourceConfig],
+) -> Option<IpAddr> {
+    #[cfg(target_os = "linux")]
+    {
+        let neighbors: Vec<IpAddr> = read_neighbor_table()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| entry.mac_address == Some(mac_address))
+            .map(|entry| entry.ip)
+            .collect();
+
+        if let Some(ip) = neighbors
+            .iter()
+            .find(|ip| ip.is_ipv4())
+            .or(neighbors.first())
+        {
+            return Some(*ip);
+        }
+    }
+
+    let leased: Vec<IpAddr> = mac_sources
+        .iter()
+        .flat_map(|source| read_mac_source(source).unwrap_or_default())
+        .filter(|entry| entry.mac_address == mac_address)
+        .filter_map(|entry| entry.ip)
+        .collect();
+
+    return leased
+        .iter()
+        .rev()
+        .find(|ip| ip.is_ipv4())
+        .or(leased.last())
+        .copied();
+}
+
+/// `find_device_address()` returns the address to ping the device at, which is its `host`, or else the current IP address of its MAC address
+pub fn find_device_address(
+    device: &DeviceConfig,
+    mac_sources: &[MacSourceConfig],
+) -> Option<String> {
+    if let Some(host) = &device.host {
+        return Some(host.clone());
     }
 
-    return Ok(String::from_utf8(arp_unwraped.stdout).unwrap());
+    let ip = discover_ip_address(device.known_mac_address()?, mac_sources);
+    if ip.is_none() {
+        debug!(
+            "No IP address found for '{}' in the neighbor table or MAC sources",
+            device.friendly_name
+        );
+    }
+
+    return ip.map(|ip| ip.to_string());
+}
+
+/// The results of a background MAC address refresh, as the `friendly_name` of each device along with its resolved MAC address
+pub type MacRefreshResults = Vec<(String, Result<MacAddress, MacResolutionError>)>;
+
+/// The `MacRefresher` struct re-resolves the MAC addresses of devices in the background, so a slow lookup doesn't hold up polling
+#[derive(Default)]
+pub struct MacRefresher {
+    refresh: Option<JoinHandle<MacRefreshResults>>,
+}
+
+impl MacRefresher {
+    /// `start()` re-resolves the MAC address of each `(friendly_name, host, source)` in `devices` in the background, unless a refresh is already running
+    pub fn start(
+        &mut self,
+        devices: Vec<(String, String, MacAddressSource)>,
+        mac_sources: &[MacSourceConfig],
+    ) {
+        if self.refresh.is_some() || devices.is_empty() {
+            return;
+        }
+
+        let mac_sources = mac_sources.to_vec();
+        self.refresh = Some(spawn(move || {
+            return devices
+                .into_iter()
+                .map(|(friendly_name, host, source)| {
+                    let result = resolve_device_mac_address(source, &host, &mac_sources);
+
+                    return (friendly_name, result);
+                })
+                .collect();
+        }));
+    }
+
+    /// `take_results()` returns the results of the background refresh once it has finished
+    pub fn take_results(&mut self) -> Option<MacRefreshResults> {
+        if !self.refresh.as_ref()?.is_finished() {
+            return None;
+        }
+
+        return self.refresh.take()?.join().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPECTED: MacAddress = MacAddress([0xf6, 0x2e, 0x3c, 0x67, 0xf1, 0x74]);
+
+    #[test]
+    fn parses_every_supported_format() {
+        for value in [
+            "f6:2e:3c:67:f1:74",
+            "f6-2e-3c-67-f1-74",
+            "f6 2e 3c 67 f1 74",
+            "f62e.3c67.f174",
+            "f62e3c67f174",
+        ] {
+            assert_eq!(value.parse::<MacAddress>().ok(), Some(EXPECTED), "{value}");
+        }
+    }
+
+    #[test]
+    fn parses_any_case_and_surrounding_whitespace() {
+        assert_eq!(
+            "F6:2E:3C:67:F1:74".parse::<MacAddress>().ok(),
+            Some(EXPECTED)
+        );
+        assert_eq!("F62e.3C67.f174".parse::<MacAddress>().ok(), Some(EXPECTED));
+        assert_eq!(
+            "  f6:2e:3c:67:f1:74\n".parse::<MacAddress>().ok(),
+            Some(EXPECTED)
+        );
+    }
+
+    #[test]
+    fn pads_single_digit_octets() {
+        assert_eq!(
+            "0:1b:3c:7:f1:74".parse::<MacAddress>().ok(),
+            Some(MacAddress([0x00, 0x1b, 0x3c, 0x07, 0xf1, 0x74]))
+        );
+    }
+
+    #[test]
+    fn displays_the_canonical_form() {
+        let mac_address: MacAddress = "F6-2E-3C-67-F1-74".parse().unwrap();
+
+        assert_eq!(mac_address.to_string(), "f6:2e:3c:67:f1:74");
+    }
+
+    #[test]
+    fn rejects_invalid_mac_addresses() {
+        for value in [
+            "",
+            "arp",
+            "f6:2e:3c:67:f1",
+            "f6:2e:3c:67:f1:74:00",
+            "f6:2e:3c:67:f1:7g",
+            "f6:2e:3c:67:f1:174",
+            "f62e.3c67.f17",
+            "f62e3c67f1",
+            "f62e3c67f17400",
+            "f6:2e:3c:+7:f1:74",
+        ] {
+            assert!(value.parse::<MacAddress>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn error_names_the_invalid_value() {
+        let error = "not-a-mac".parse::<MacAddress>().unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .starts_with("'not-a-mac' is not a valid MAC address")
+        );
+    }
 }
@@ -1,203 +1,541 @@
+#![allow(clippy::needless_return)]
+
+mod cli;
+use cli::{Cli, Command};
 mod config;
-use config::get_config;
+mod dependencies;
+mod discovery;
+mod health;
+use config::{
+    DeviceConfig, MacAddressSource, NutConfig, NutjobConfig, UnreachablePolicy, UpsConfig,
+    get_config, load_config, string_to_level_filter,
+};
+use dependencies::wake_stages;
+use discovery::{Subnet, discover_devices, format_devices, merge_into_config};
 mod monitoring;
 mod state;
-use monitoring::{get_ups_status, is_device_online};
+use monitoring::NutClient;
 mod wakeonlan;
 use wakeonlan::wakeonlan;
+mod leases;
 mod mac;
-
+use mac::{MacRefresher, resolve_mac_address};
+#[cfg(target_os = "linux")]
+mod neighbor;
+mod ping;
+mod power;
+mod probing;
+use power::{FeedStatus, PowerFeeds};
+use probing::probe_devices;
+mod restoration;
+use restoration::Restoration;
+mod validation;
+use validation::{ConfigErrors, format_problems};
+mod watcher;
+use watcher::ConfigWatcher;
+
+use clap::Parser;
 use log::{LevelFilter, debug, error, info, warn};
+use simple_logger::SimpleLogger;
 
-use std::io::{Error, ErrorKind};
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::exit;
 use std::thread::sleep;
 use std::time::{Duration, Instant, SystemTime};
 
-use crate::monitoring::UPSStatus;
+use crate::monitoring::UPSStatusError;
 use crate::state::{
-    can_attempt_wake, get_state, init_state, mark_device_online, mark_online_devices,
-    mark_wol_attempted, reset_device_states, save_state, update_ups_state, was_device_online,
+    get_state, init_state, mark_device_online, needs_mac_refresh, read_state_from_file, save_state,
+    set_state_path, update_mac_address, update_tracked_devices, update_ups_state,
 };
 
-fn string_to_level_filter(log_level: &String) -> Result<LevelFilter, Error> {
-    return match log_level.to_lowercase().as_str() {
-        "off" => Ok(LevelFilter::Off),
-        "trace" => Ok(LevelFilter::Trace),
-        "debug" => Ok(LevelFilter::Debug),
-        "info" => Ok(LevelFilter::Info),
-        "warn" => Ok(LevelFilter::Warn),
-        "error" => Ok(LevelFilter::Error),
-        _ => Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Invalid log level provided",
-        )),
+fn main() {
+    // The HTTP client used by health checks logs every request, which drowns out nutjob's own logs
+    SimpleLogger::new()
+        .with_module_level("ureq", LevelFilter::Warn)
+        .with_module_level("ureq_proto", LevelFilter::Warn)
+        .with_module_level("rustls", LevelFilter::Warn)
+        .init()
+        .unwrap();
+
+    let cli = Cli::parse();
+
+    set_state_path(&cli.state);
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(&cli.config),
+        Command::CheckConfig => print_config_problems(&cli.config),
+        Command::Status => print_status(),
+        Command::Wake { device } => wake_device(&cli.config, &device),
+        Command::Resolve { host } => resolve_host(&host),
+        Command::Discover { subnet, merge, ups } => {
+            discover(&cli.config, &subnet, merge, ups.as_deref())
+        }
+    }
+}
+
+/// `print_config_problems()` validates the configuration file and prints every problem found, exiting with a non-zero code if it can't be used
+fn print_config_problems(config_path: &str) {
+    match load_config(config_path, true) {
+        Ok((config, warnings)) => {
+            if !warnings.is_empty() {
+                println!("{}\n", format_problems(&warnings));
+            }
+
+            if config
+                .devices
+                .iter()
+                .any(|device| !device.depends_on.is_empty())
+            {
+                let devices: Vec<&DeviceConfig> = config.devices.iter().collect();
+                let (stages, _) = wake_stages(&devices);
+
+                println!("Wake order:");
+                for (index, stage) in stages.iter().enumerate() {
+                    println!("\t{}) {}", index + 1, stage.join(", "));
+                }
+                println!();
+            }
+
+            println!(
+                "Configuration file is valid ({} device(s), {} warning(s))",
+                config.devices.len(),
+                warnings.len()
+            );
+        }
+        Err(errors) => {
+            println!("{errors}\n");
+            println!("Configuration file is invalid");
+            exit(1);
+        }
+    }
+}
+
+/// `exit_with_config_errors()` logs every problem in `errors` and exits with a non-zero code
+fn exit_with_config_errors(errors: ConfigErrors) -> ! {
+    error!(target: "Config", "Unable to load config file! Make sure it is accessible and formatted correctly!\n{errors}");
+    exit(1);
+}
+
+/// `print_status()` prints the state persisted by the service
+fn print_status() {
+    let state = read_state_from_file();
+
+    if state.ups.is_empty() {
+        println!("No UPS status recorded");
+    }
+
+    for (name, ups_status) in &state.ups {
+        println!("{name} | {ups_status}");
+    }
+
+    if state.devices.is_empty() {
+        println!("No devices tracked");
+        return;
+    }
+
+    println!("Devices:");
+    for device in state.devices {
+        let wol_sent = match device
+            .wol_sent_at
+            .and_then(|sent_at| sent_at.elapsed().ok())
+        {
+            Some(elapsed) => format!("{}s ago", elapsed.as_secs()),
+            None => String::from("never"),
+        };
+
+        println!(
+            "\t- {}: {} | IP: {} | Online before shutdown: {} | WoL sent: {wol_sent}",
+            device.friendly_name,
+            if device.online { "ONLINE" } else { "OFFLINE" },
+            device.address.as_deref().unwrap_or("unknown"),
+            if device.online_before_shutdown {
+                "yes"
+            } else {
+                "no"
+            }
+        );
+    }
+}
+
+/// `wake_device()` sends a WoL packet to the configured device named `friendly_name`
+fn wake_device(config_path: &str, friendly_name: &str) {
+    let config = get_config(config_path).unwrap_or_else(|e| exit_with_config_errors(e));
+
+    let Some(device) = config
+        .devices
+        .iter()
+        .find(|device| device.friendly_name == friendly_name)
+    else {
+        error!("No valid device named '{friendly_name}' in the configuration file");
+        exit(1);
+    };
+
+    let Some(mac_address) = device.known_mac_address() else {
+        error!("The MAC address of '{friendly_name}' hasn't been resolved yet");
+        exit(1);
     };
+
+    if wakeonlan(mac_address, &device.friendly_name).is_err() {
+        exit(1);
+    }
 }
 
-fn main() {
-    simple_logger::init().unwrap();
+/// `resolve_host()` prints the MAC address of `host`
+fn resolve_host(host: &str) {
+    match resolve_mac_address(host) {
+        Ok(mac_address) => println!("{mac_address}"),
+        Err(e) => {
+            error!("Unable to resolve MAC address for '{host}': {e}");
+            exit(1);
+        }
+    }
+}
 
-    let config = get_config();
+/// `discover()` sweeps `subnet` for online hosts, printing them as device entries or adding them to the configuration file at `config_path` if `merge` is set
+fn discover(config_path: &str, subnet: &Subnet, merge: bool, ups: Option<&str>) {
+    let existing = if merge {
+        match load_config(config_path, false) {
+            Ok((config, _)) => Some(config),
+            Err(errors) => exit_with_config_errors(errors),
+        }
+    } else {
+        None
+    };
 
-    log::set_max_level(string_to_level_filter(&config.log_level).unwrap_or(LevelFilter::Trace));
+    let ping_config = existing
+        .as_ref()
+        .map(|config| config.ping)
+        .unwrap_or_default();
 
-    if init_state(&config.devices).is_err() {
-        panic!("Unable to initialize state management!");
+    let devices = discover_devices(subnet, existing.as_ref(), &ping_config);
+
+    if devices.is_empty() {
+        info!("No new devices found");
+        return;
     }
 
-    let initial_state = get_state();
+    if !merge {
+        print!("devices:\n{}", format_devices(&devices, ups, "  "));
+        return;
+    }
+
+    match merge_into_config(config_path, &devices, ups) {
+        Ok(()) => info!("Added {} device(s) to {config_path}", devices.len()),
+        Err(e) => {
+            error!("Unable to merge discovered devices into {config_path}: {e}");
 
-    // Check if UPS was on battery prior to FSD
-    let mut awaiting_ac = false;
-    let mut restoring = false;
-    let mut restoration_started: Option<SystemTime> = None;
-    let mut waking_started = false;
-    let mut resotred_devices: Vec<String> = Vec::new();
-    let mut unrestored_devices: Vec<String> = Vec::new();
-    let mut skipped_devices: Vec<String> = Vec::new();
-    if initial_state.ups.currently_on_battery {
-        info!("Service is restoring from a UPS outage");
-        restoring = true;
+            if ups.is_none() && existing.is_some_and(|config| config.ups.len() > 1) {
+                error!("Choose the UPS powering the discovered devices with --ups <name>");
+            }
+            exit(1);
+        }
     }
+}
 
-    let interval = Duration::from_secs(config.nut.polling_interval.into());
-    let mut next_time = Instant::now() + interval;
+/// `reload_config()` loads and validates the configuration file at `config_path` for a running service
+///
+/// It returns `None` if the new configuration is invalid, in which case the current configuration should be kept
+fn reload_config(config_path: &str) -> Option<NutjobConfig> {
+    return match get_config(config_path) {
+        Ok(config) => Some(config),
+        Err(errors) => {
+            error!(target: "Config", "Rejected reloaded config file, keeping the current configuration\n{errors}");
+            None
+        }
+    };
+}
 
-    loop {
-        let _ups_status = get_ups_status(
-            &config.nut.ups_name,
-            &config.nut.host,
-            &config.nut.username,
-            &config.nut.password,
-        );
+/// `sync_nut_clients()` keeps a [`NutClient`] for every UPS in `ups_configs`, keeping the sessions of UPSes whose configuration didn't change
+fn sync_nut_clients(nut_clients: &mut BTreeMap<String, NutClient>, ups_configs: &[UpsConfig]) {
+    nut_clients.retain(|name, nut_client| {
+        ups_configs
+            .iter()
+            .any(|ups| ups.name == *name && ups == nut_client.ups())
+    });
+
+    for ups in ups_configs {
+        nut_clients
+            .entry(ups.name.clone())
+            .or_insert_with(|| NutClient::new(ups));
+    }
+}
 
-        if _ups_status.is_err() {
-            error!(target: "UPS", "Unable to establish connection to {}@{}", config.nut.ups_name, config.nut.host);
+/// `unreachable_status()` returns [`FeedStatus::Unreachable`] if the NUT server of `nut_client` has been unreachable for longer than the grace period in `nut_config`, and that is treated as an outage
+fn unreachable_status(nut_client: &NutClient, nut_config: &NutConfig) -> Option<FeedStatus> {
+    if nut_config.on_unreachable == UnreachablePolicy::Ignore {
+        return None;
+    }
+
+    let since = nut_client.unreachable_since()?;
+    let grace_period = Duration::from_secs(nut_config.unreachable_grace_period.into());
+
+    if SystemTime::now().duration_since(since).unwrap_or_default() < grace_period {
+        return None;
+    }
+
+    return Some(FeedStatus::Unreachable { since });
+}
+
+/// `unavailable_status()` returns [`FeedStatus::Dead`] if the UPS of `nut_client` hasn't been reporting (see [`UPSStatusError::UpsUnavailable`]) for longer than the grace period in `nut_config`
+fn unavailable_status(nut_client: &NutClient, nut_config: &NutConfig) -> Option<FeedStatus> {
+    let since = nut_client.unavailable_since()?;
+    let grace_period = Duration::from_secs(nut_config.unreachable_grace_period.into());
+
+    if SystemTime::now().duration_since(since).unwrap_or_default() < grace_period {
+        return None;
+    }
+
+    return Some(FeedStatus::Dead);
+}
+
+/// `sync_restorations()` tracks the outage/restoration of every distinct set of power feeds used by `devices`, keeping the progress of feeds that are still used
+///
+/// Newly tracked feeds that were on battery according to `statuses` start restoring straight away
+fn sync_restorations(
+    restorations: &mut BTreeMap<PowerFeeds, Restoration>,
+    devices: &[DeviceConfig],
+    statuses: &BTreeMap<String, FeedStatus>,
+) {
+    let power_feeds: Vec<PowerFeeds> = devices.iter().map(PowerFeeds::of).collect();
+
+    restorations.retain(|feeds, _| power_feeds.contains(feeds));
+
+    for feeds in power_feeds {
+        if restorations.contains_key(&feeds) {
             continue;
         }
 
-        let ups_status = _ups_status.unwrap();
+        let was_on_battery = feeds
+            .status(statuses)
+            .is_some_and(|ups_status| ups_status.currently_on_battery);
+
+        let restoration = Restoration::new(&feeds.to_string(), was_on_battery);
+        restorations.insert(feeds, restoration);
+    }
+}
 
-        let UPSStatus {
-            currently_on_battery: ups_currently_on_battery,
-            battery_percentage: ups_battery_percentage,
-            load_percentage: ups_load_percentage,
-        } = ups_status;
-        let _ = update_ups_state(ups_status);
+/// `run()` monitors the UPSes and wakes devices once power is restored
+fn run(config_path: &str) {
+    let mut config = get_config(config_path).unwrap_or_else(|e| exit_with_config_errors(e));
 
-        debug!(target: "UPS", "UPS Status: {} | UPS Load: {ups_load_percentage}% | UPS Battery: {ups_battery_percentage}%", if ups_currently_on_battery { "ON BAT" } else { "ONLINE" });
+    log::set_max_level(string_to_level_filter(&config.log_level).unwrap_or(LevelFilter::Trace));
 
-        // Check if devices are online
-        for device in &config.devices {
-            let _ =
-                mark_device_online(device.friendly_name.clone(), is_device_online(&device.host));
-        }
+    let mut config_watcher = match ConfigWatcher::new(config_path) {
+        Ok(config_watcher) => config_watcher,
+        Err(e) => panic!("Unable to watch config file for changes! ({e})"),
+    };
 
-        if ups_currently_on_battery && !awaiting_ac {
-            let _ = mark_online_devices();
+    if init_state(&config.ups, &config.devices).is_err() {
+        panic!("Unable to initialize state management!");
+    }
 
-            info!(target: "UPS", "UPS switched to battery power");
+    let initial_state = get_state();
 
-            awaiting_ac = true;
-        } else if restoring || (awaiting_ac && !ups_currently_on_battery) {
-            restoring = true;
-            awaiting_ac = false;
+    // Check if each set of power feeds was on battery prior to FSD
+    let initial_statuses: BTreeMap<String, FeedStatus> = initial_state
+        .ups
+        .into_iter()
+        .map(|(name, ups_status)| (name, FeedStatus::Reporting(ups_status)))
+        .collect();
+    let mut restorations: BTreeMap<PowerFeeds, Restoration> = BTreeMap::new();
+    sync_restorations(&mut restorations, &config.devices, &initial_statuses);
 
-            if restoration_started.is_none() {
-                restoration_started = Some(SystemTime::now());
-                info!(target: "UPS", "UPS switched to AC power, restoring devices");
-            }
+    let mut mac_refresher = MacRefresher::default();
 
-            let restoration_time_elapsed = restoration_started.unwrap().elapsed().unwrap();
+    let mut nut_clients: BTreeMap<String, NutClient> = BTreeMap::new();
+    sync_nut_clients(&mut nut_clients, &config.ups);
 
-            if restoration_time_elapsed < Duration::from_secs(config.wol.restore_delay.into()) {
-                warn!(
-                    "Waiting {} more second(s) before waking devices",
-                    (config.wol.restore_delay as u64) - restoration_time_elapsed.as_secs()
-                );
-            } else if ups_battery_percentage < config.wol.min_battery_percentage {
-                warn!(
-                    "Waiting for battery to reach minimum percentage before waking devices ({}%/{}%)",
-                    ups_battery_percentage, config.wol.min_battery_percentage
+    // UPSes assumed to have lost power because their NUT server is unreachable
+    let mut unreachable_ups: BTreeSet<String> = BTreeSet::new();
+
+    let mut interval = Duration::from_secs(config.nut.polling_interval.into());
+    let mut next_time = Instant::now() + interval;
+
+    loop {
+        if config_watcher.should_reload() {
+            info!(target: "Config", "Reloading configuration file");
+
+            if let Some(new_config) = reload_config(config_path) {
+                config = new_config;
+
+                log::set_max_level(
+                    string_to_level_filter(&config.log_level).unwrap_or(LevelFilter::Trace),
                 );
-            } else {
-                if !waking_started {
-                    info!(
-                        "Waking devices; {} seconds have elapsed & battery >= {}%",
-                        config.wol.restore_delay, config.wol.min_battery_percentage
-                    );
-                    waking_started = true;
+                interval = Duration::from_secs(config.nut.polling_interval.into());
+
+                sync_nut_clients(&mut nut_clients, &config.ups);
+                unreachable_ups.retain(|name| nut_clients.contains_key(name));
+
+                // Keep restoration progress for UPSes and devices that are still configured
+                let _ = update_tracked_devices(&config.ups, &config.devices);
+                sync_restorations(&mut restorations, &config.devices, &BTreeMap::new());
+
+                let is_configured = |friendly_name: &String| {
+                    config
+                        .devices
+                        .iter()
+                        .any(|device| device.friendly_name == *friendly_name)
+                };
+                for restoration in restorations.values_mut() {
+                    restoration.retain_devices(is_configured);
                 }
 
-                for device in &config.devices {
-                    if !was_device_online(&device.friendly_name) {
-                        info!(
-                            "Skipping restoration for '{}' since it was offline before UPS switched to battery power",
-                            device.friendly_name
-                        );
+                info!(target: "Config", "Reloaded configuration file successfully ({} UPS(es), {} device(s))", config.ups.len(), config.devices.len());
+            }
+        }
 
-                        skipped_devices.push(device.friendly_name.clone());
-                        continue;
-                    }
+        let mut ups_statuses: BTreeMap<String, FeedStatus> = BTreeMap::new();
 
-                    if is_device_online(&device.host) {
-                        if !resotred_devices.contains(&device.friendly_name) {
-                            resotred_devices.push(device.friendly_name.clone());
-                            unrestored_devices
-                                .retain(|friendly_name| *friendly_name != device.friendly_name);
+        for ups in &config.ups {
+            let Some(nut_client) = nut_clients.get_mut(&ups.name) else {
+                continue;
+            };
 
-                            info!("{} is online!", device.friendly_name);
-                        }
-                        continue;
-                    }
+            let ups_status = match nut_client.get_ups_status() {
+                Ok(ups_status) => ups_status,
+                Err(e @ UPSStatusError::UpsUnavailable(_)) => {
+                    warn!(target: "UPS", "{} | {e}", ups.name);
 
-                    unrestored_devices.push(device.friendly_name.clone());
+                    // A driver that briefly loses the UPS (or stale data) isn't an outage, so the UPS is only dead once the grace period is over
+                    if let Some(feed_status) = unavailable_status(nut_client, &config.nut) {
+                        ups_statuses.insert(ups.name.clone(), feed_status);
+                    }
+                    continue;
+                }
+                Err(e @ UPSStatusError::UnknownStatus(_)) => {
+                    // Skip this poll rather than guess, so the outage/restoration waits for a known status
+                    warn!(target: "UPS", "{} | {e}, skipping this poll", ups.name);
+                    continue;
+                }
+                Err(e) => {
+                    if let UPSStatusError::Reconnecting(_) = e {
+                        debug!(target: "UPS", "{} | {e}", ups.name);
+                    } else {
+                        error!(target: "UPS", "Unable to get status of {}@{}: {e}", ups.ups_name, ups.host);
+                    }
 
-                    if can_attempt_wake(&device.friendly_name, config.wol.reattempt_delay) {
-                        if wakeonlan(&device.mac_address, &device.friendly_name).is_ok() {
-                            let _ = mark_wol_attempted(&device.friendly_name);
+                    // Until the grace period is over, the status of the UPS is unknown
+                    if let Some(feed_status) = unreachable_status(nut_client, &config.nut) {
+                        if unreachable_ups.insert(ups.name.clone()) {
+                            warn!(target: "UPS", "{} | NUT server has been unreachable for over {} second(s), assuming the UPS lost power", ups.name, config.nut.unreachable_grace_period);
                         }
-                    } else {
-                        debug!(target: "WoL", "Waiting for {} seconds to elapse before attempting to wake {} again", config.wol.reattempt_delay, device.friendly_name);
+
+                        ups_statuses.insert(ups.name.clone(), feed_status);
                     }
+                    continue;
                 }
+            };
 
-                if unrestored_devices.is_empty() {
-                    info!("UPS on AC power and all devices restrored!");
-                    restoring = false;
-                    restoration_started = None;
-                    waking_started = false;
-                } else if restoration_time_elapsed
-                    > Duration::from_secs(config.wol.restore_timeout.into())
-                {
-                    warn!(
-                        "Some devices failed to wake within the timeout period\n\t\t\t\t\t- {}",
-                        unrestored_devices.join("\n\t\t\t\t\t- ")
-                    );
-                    restoring = false;
-                    restoration_started = None;
-                    waking_started = false;
-                }
+            if unreachable_ups.remove(&ups.name) {
+                info!(target: "UPS", "{} | NUT server is reachable again", ups.name);
+            }
+
+            debug!(target: "UPS", "{} | {ups_status}", ups.name);
+
+            let _ = update_ups_state(&ups.name, ups_status.clone());
+            ups_statuses.insert(ups.name.clone(), FeedStatus::Reporting(ups_status));
+        }
+
+        // Check if devices are online, once per cycle, only switching them between online and offline after enough checks in a row
+        let mut snapshot = probe_devices(&config.devices, &config.mac_sources, &config.ping);
+        for device in &config.devices {
+            let Some(probe) = snapshot.get(&device.friendly_name).cloned() else {
+                continue;
+            };
+
+            let liveness = config.liveness.with_overrides(&device.liveness);
+            if let Ok(online) = mark_device_online(
+                device.friendly_name.clone(),
+                probe.passed,
+                probe.address,
+                &liveness,
+            ) {
+                snapshot.set_online(&device.friendly_name, online);
+            }
+        }
 
-                if !skipped_devices.is_empty() {
-                    warn!(
-                        "Some devices did not wake because they were offline before UPS switched to battery power\n\t\t\t\t\t- {}",
-                        skipped_devices.join("\n\t\t\t\t\t- ")
-                    );
+        // Learn the MAC addresses of pending `arp`/`dhcp` devices, and keep the cached ones up to date
+        if let Some(results) = mac_refresher.take_results() {
+            for (friendly_name, result) in results {
+                match result {
+                    Ok(mac_address) => {
+                        let _ = update_mac_address(&friendly_name, mac_address);
+                    }
+                    Err(e) => debug!("Unable to refresh MAC address of '{friendly_name}': {e}"),
                 }
             }
-        } else if !ups_currently_on_battery && !restoring {
-            resotred_devices.clear();
-            unrestored_devices.clear();
-            skipped_devices.clear();
+        }
 
-            let _ = reset_device_states();
-            debug!("Reset device states");
+        let online_devices: Vec<String> = get_state()
+            .devices
+            .into_iter()
+            .filter(|device| device.online)
+            .map(|device| device.friendly_name)
+            .collect();
+        mac_refresher.start(
+            config
+                .devices
+                .iter()
+                .filter(|device| {
+                    // Only online devices are in the neighbor table, while lease files can be read at any time
+                    device.resolves_mac_address()
+                        && (device.mac_source == Some(MacAddressSource::Dhcp)
+                            || online_devices.contains(&device.friendly_name))
+                        && needs_mac_refresh(&device.friendly_name, config.wol.mac_refresh_interval)
+                })
+                .filter_map(|device| {
+                    Some((
+                        device.friendly_name.clone(),
+                        device.host.clone()?,
+                        device.mac_source?,
+                    ))
+                })
+                .collect(),
+            &config.mac_sources,
+        );
+
+        let unavailable_devices: Vec<String> = restorations
+            .values()
+            .flat_map(|restoration| restoration.unavailable_devices().cloned())
+            .collect();
+
+        for (power_feeds, restoration) in restorations.iter_mut() {
+            let Some(ups_status) = power_feeds.status(&ups_statuses) else {
+                continue;
+            };
+
+            restoration.set_lost_contact_at(power_feeds.lost_contact_at(&ups_statuses));
+
+            let devices: Vec<&DeviceConfig> = config
+                .devices
+                .iter()
+                .filter(|device| PowerFeeds::of(device) == *power_feeds)
+                .collect();
+
+            restoration.update(
+                &power_feeds.to_string(),
+                &ups_status,
+                &devices,
+                &config,
+                &snapshot,
+                &unavailable_devices,
+            );
         }
 
         let _ = save_state();
 
-        sleep(next_time - Instant::now());
-        next_time += interval;
+        // Skip the cycles that were missed if this one overran, rather than running them back to back
+        let now = Instant::now();
+        if next_time > now {
+            sleep(next_time - now);
+            next_time += interval;
+        } else {
+            debug!(
+                "Polling cycle took {}ms longer than the polling interval",
+                (now - next_time).as_millis()
+            );
+            next_time = now + interval;
+        }
     }
 }
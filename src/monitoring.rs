@@ -1,75 +1,781 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::process::Command;
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::Once;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::thread::spawn;
+use std::time::{Duration, Instant, SystemTime};
 
 use bincode::{Decode, Encode};
+use log::{debug, error, warn};
 use rups::blocking::Connection;
-use rups::{Auth, ConfigBuilder};
+use rups::{Auth, ClientError, Config, ConfigBuilder, NutError};
 
-/// The `is_device_online` function checks to see if a device is "online" by pinging the device. If the exit code is 0 then it returns true, otherwise it returns false.
-pub fn is_device_online(host: &str) -> bool {
-    #[cfg(target_os = "windows")]
-    let ping_output = Command::new("cmd")
-        .arg("/C")
-        .arg(format!("ping -n 1 {host}"))
-        .output();
+use crate::config::{HealthCheckConfig, PingConfig, UpsConfig};
+use crate::health::health_check;
 
-    #[cfg(not(target_os = "windows"))]
-    let ping_output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ping -c 1 {host}"))
-        .output();
+/// The variables queried for the status of a UPS
+const UPS_STATUS_VARS: [&str; 10] = [
+    "ups.status",
+    "battery.charge",
+    "ups.load",
+    "battery.runtime",
+    "battery.voltage",
+    "input.voltage",
+    "output.voltage",
+    "ups.realpower",
+    "ups.temperature",
+    "ups.model",
+];
 
-    if ping_output.is_err() {
-        return false;
+/// Time to wait for a TCP connection to the NUT server
+const NUT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest time connecting and logging in to the NUT server, or a single query, may take before the session is considered lost
+///
+/// `rups` doesn't set a read timeout, so a NUT server that loses power mid-session would otherwise block until TCP gives up (around 15 minutes on Linux).
+const NUT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait before reconnecting to a NUT server after the first failed attempt, doubled after each further failure
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The longest time to wait before reconnecting to a NUT server
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Reports that pinging isn't possible only once, rather than for every device on every poll
+static PING_UNAVAILABLE: Once = Once::new();
+
+/// The `is_device_online` function checks to see if a device is "online" with its health `check` (see [`HealthCheckConfig`]), using `ping_config` for ICMP and as the default timeout. If the check passes then it returns true, otherwise it returns false.
+pub fn is_device_online(host: &str, check: &HealthCheckConfig, ping_config: &PingConfig) -> bool {
+    return match health_check(check, ping_config).is_online(host) {
+        Ok(online) => online,
+        Err(e) if *check == HealthCheckConfig::Icmp => {
+            PING_UNAVAILABLE.call_once(|| {
+                error!("Unable to ping devices, so every device is considered offline: {e}");
+            });
+            false
+        }
+        Err(e) => {
+            warn!("Unable to check whether '{host}' is online with {check}: {e}");
+            false
+        }
+    };
+}
+
+/// A flag in the `ups.status` variable reported by NUT
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsFlag {
+    /// `OL`: on line (mains) power
+    Online,
+    /// `OB`: on battery
+    OnBattery,
+    /// `LB`: low battery
+    LowBattery,
+    /// `HB`: high battery
+    HighBattery,
+    /// `RB`: the battery needs to be replaced
+    ReplaceBattery,
+    /// `CHRG`: the battery is charging
+    Charging,
+    /// `DISCHRG`: the battery is discharging
+    Discharging,
+    /// `BYPASS`: the load is powered straight from mains, bypassing the battery
+    Bypass,
+    /// `CAL`: running a runtime calibration (self-test)
+    Calibrating,
+    /// `OFF`: the output is off, so the load has no power
+    Off,
+    /// `OVER`: overloaded
+    Overloaded,
+    /// `TRIM`: lowering a high mains voltage
+    Trimming,
+    /// `BOOST`: raising a low mains voltage
+    Boosting,
+    /// `FSD`: forced shutdown, the load is being shut down before the UPS cuts power
+    ForcedShutdown,
+}
+
+impl UpsFlag {
+    /// `UpsFlag::from_token()` returns the flag written as `token` in `ups.status`, or `None` if it isn't known
+    pub fn from_token(token: &str) -> Option<Self> {
+        return match token {
+            "OL" => Some(Self::Online),
+            "OB" => Some(Self::OnBattery),
+            "LB" => Some(Self::LowBattery),
+            "HB" => Some(Self::HighBattery),
+            "RB" => Some(Self::ReplaceBattery),
+            "CHRG" => Some(Self::Charging),
+            "DISCHRG" => Some(Self::Discharging),
+            "BYPASS" => Some(Self::Bypass),
+            "CAL" => Some(Self::Calibrating),
+            "OFF" => Some(Self::Off),
+            "OVER" => Some(Self::Overloaded),
+            "TRIM" => Some(Self::Trimming),
+            "BOOST" => Some(Self::Boosting),
+            "FSD" => Some(Self::ForcedShutdown),
+            _ => None,
+        };
+    }
+
+    /// `token()` returns how the flag is written in `ups.status`
+    pub fn token(&self) -> &'static str {
+        return match self {
+            Self::Online => "OL",
+            Self::OnBattery => "OB",
+            Self::LowBattery => "LB",
+            Self::HighBattery => "HB",
+            Self::ReplaceBattery => "RB",
+            Self::Charging => "CHRG",
+            Self::Discharging => "DISCHRG",
+            Self::Bypass => "BYPASS",
+            Self::Calibrating => "CAL",
+            Self::Off => "OFF",
+            Self::Overloaded => "OVER",
+            Self::Trimming => "TRIM",
+            Self::Boosting => "BOOST",
+            Self::ForcedShutdown => "FSD",
+        };
+    }
+}
+
+/// The flags of a UPS, parsed from its `ups.status` variable
+#[derive(Encode, Decode, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpsFlags {
+    flags: Vec<UpsFlag>,
+}
+
+impl UpsFlags {
+    /// `UpsFlags::parse()` parses the space separated flags of `ups.status`, ignoring (and logging) any it doesn't know
+    pub fn parse(status: &str) -> Self {
+        let mut flags = UpsFlags::default();
+
+        for token in status.split_whitespace() {
+            match UpsFlag::from_token(token) {
+                Some(flag) => flags.insert(flag),
+                None => debug!(target: "UPS", "Ignoring unknown UPS status flag '{token}'"),
+            }
+        }
+
+        return flags;
+    }
+
+    /// `insert()` adds `flag`, if it isn't set already
+    pub fn insert(&mut self, flag: UpsFlag) {
+        if !self.contains(flag) {
+            self.flags.push(flag);
+        }
+    }
+
+    pub fn contains(&self, flag: UpsFlag) -> bool {
+        return self.flags.contains(&flag);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = UpsFlag> {
+        return self.flags.iter().copied();
+    }
+
+    /// `lost_power()` returns true if the load has lost (or is about to lose) mains power:
+    ///
+    /// - `FSD`: the load is being shut down, even if mains power is back
+    /// - `OFF`: the output is off, so the load has no power
+    /// - `OB`: on battery, unless the UPS is only draining it for a calibration (`CAL`)
+    ///
+    /// Anything else, including `BYPASS`, counts as mains power. A status without any of these or `OL` is unknown (see [`UpsFlags::has_power_state`]).
+    pub fn lost_power(&self) -> bool {
+        if self.contains(UpsFlag::ForcedShutdown) || self.contains(UpsFlag::Off) {
+            return true;
+        }
+
+        return self.contains(UpsFlag::OnBattery) && !self.contains(UpsFlag::Calibrating);
+    }
+
+    /// `has_power_state()` returns true if the status says whether the UPS is on mains power (`OL`, `OB`, `OFF` or `FSD`), rather than being empty or only describing the battery
+    pub fn has_power_state(&self) -> bool {
+        return [
+            UpsFlag::Online,
+            UpsFlag::OnBattery,
+            UpsFlag::Off,
+            UpsFlag::ForcedShutdown,
+        ]
+        .into_iter()
+        .any(|flag| self.contains(flag));
+    }
+
+    /// `shutting_down()` returns true if the devices powered by the UPS are definitely shut down (or being shut down): by a forced shutdown (`FSD`), because the output is off (`OFF`) or because the battery is low (`LB`) while the UPS has lost power
+    pub fn shutting_down(&self) -> bool {
+        return self.contains(UpsFlag::ForcedShutdown)
+            || self.contains(UpsFlag::Off)
+            || (self.contains(UpsFlag::LowBattery) && self.lost_power());
     }
+}
 
-    let ping_success = ping_output.unwrap().status.code() == Some(0);
+impl fmt::Display for UpsFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.flags.is_empty() {
+            return write!(f, "UNKNOWN");
+        }
 
-    return ping_success;
+        let tokens: Vec<&str> = self.flags.iter().map(UpsFlag::token).collect();
+        write!(f, "{}", tokens.join(" "))
+    }
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
+/// The status of a UPS, with `None` for any value the UPS doesn't report
+#[derive(Encode, Decode, Debug, Clone, Default)]
 pub struct UPSStatus {
+    /// Whether the UPS has lost mains power, see [`UpsFlags::lost_power`]
     pub currently_on_battery: bool,
-    pub battery_percentage: u8,
-    pub load_percentage: u8,
+    pub flags: UpsFlags,
+    /// `battery.charge`
+    pub battery_percentage: Option<f64>,
+    /// `ups.load`
+    pub load_percentage: Option<f64>,
+    /// `battery.runtime`, in seconds
+    pub battery_runtime: Option<f64>,
+    /// `battery.voltage`
+    pub battery_voltage: Option<f64>,
+    /// `input.voltage`
+    pub input_voltage: Option<f64>,
+    /// `output.voltage`
+    pub output_voltage: Option<f64>,
+    /// `ups.realpower`, in watts
+    pub real_power: Option<f64>,
+    /// `ups.temperature`, in degrees Celsius
+    pub temperature: Option<f64>,
+    /// `ups.model`
+    pub model: Option<String>,
 }
 
-/// The `get_ups_status` function queries specific information (see [`UPSStatus`]) from the NUT server specified in the config file
-pub fn get_ups_status(
+/// `format_value()` formats `value` followed by `unit`, or "unknown" if it isn't known
+pub fn format_value(value: Option<f64>, unit: &str) -> String {
+    return match value {
+        Some(value) => format!("{value}{unit}"),
+        None => String::from("unknown"),
+    };
+}
+
+impl fmt::Display for UPSStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "UPS Status: {} ({}) | UPS Load: {} | UPS Battery: {}",
+            if self.currently_on_battery {
+                "ON BAT"
+            } else {
+                "ONLINE"
+            },
+            self.flags,
+            format_value(self.load_percentage, "%"),
+            format_value(self.battery_percentage, "%")
+        )?;
+
+        // Only the extra values the UPS reports are shown
+        if let Some(runtime) = self.battery_runtime {
+            let runtime = runtime.round() as u64;
+            write!(f, " | Runtime: {}m {}s", runtime / 60, runtime % 60)?;
+        }
+
+        for (label, value, unit) in [
+            ("Battery Voltage", self.battery_voltage, "V"),
+            ("Input Voltage", self.input_voltage, "V"),
+            ("Output Voltage", self.output_voltage, "V"),
+            ("Power", self.real_power, "W"),
+            ("Temperature", self.temperature, "°C"),
+        ] {
+            if value.is_some() {
+                write!(f, " | {label}: {}", format_value(value, unit))?;
+            }
+        }
+
+        if let Some(model) = &self.model {
+            write!(f, " | Model: {model}")?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// Errors that can occur while querying the NUT server
+#[derive(Debug)]
+pub enum UPSStatusError {
+    /// The NUT server rejected the configured username/password
+    AuthenticationFailed(NutError),
+    /// The NUT server requires authentication, but no username/password is configured
+    AuthenticationRequired(NutError),
+    /// The NUT server is reachable, but the UPS is not reporting (e.g. it has run out of battery and shut off)
+    UpsUnavailable(NutError),
+    /// The UPS is reporting, but its `ups.status` (if any) doesn't say whether it is on mains power
+    UnknownStatus(Option<String>),
+    /// Waiting to reconnect to the NUT server after failing to, for the remaining time
+    Reconnecting(Duration),
+    /// Any other error, such as the NUT server being unreachable
+    Client(ClientError),
+}
+
+impl fmt::Display for UPSStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AuthenticationFailed(e) => {
+                write!(f, "NUT server rejected the configured credentials ({e})")
+            }
+            Self::AuthenticationRequired(e) => write!(
+                f,
+                "NUT server requires authentication, but no username/password is configured ({e})"
+            ),
+            Self::UpsUnavailable(e) => {
+                write!(f, "UPS is not reporting, it may be powered off ({e})")
+            }
+            Self::UnknownStatus(Some(status)) => write!(
+                f,
+                "UPS status '{status}' doesn't say whether it is on mains power"
+            ),
+            Self::UnknownStatus(None) => write!(f, "UPS didn't report its status"),
+            Self::Reconnecting(remaining) => write!(
+                f,
+                "Not connected to the NUT server, reconnecting in {}s",
+                remaining.as_secs_f64().ceil()
+            ),
+            Self::Client(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for UPSStatusError {}
+
+/// `classify_client_error()` converts a [`ClientError`] into a [`UPSStatusError`], separating authentication and UPS availability errors from everything else
+fn classify_client_error(error: ClientError, authenticated: bool) -> UPSStatusError {
+    return match error {
+        ClientError::Nut(
+            e @ (NutError::AccessDenied
+            | NutError::InvalidUsername
+            | NutError::InvalidPassword
+            | NutError::UsernameRequired
+            | NutError::PasswordRequired),
+        ) => {
+            if authenticated {
+                UPSStatusError::AuthenticationFailed(e)
+            } else {
+                UPSStatusError::AuthenticationRequired(e)
+            }
+        }
+        ClientError::Nut(e @ (NutError::DriverNotConnected | NutError::DataStale)) => {
+            UPSStatusError::UpsUnavailable(e)
+        }
+        e => UPSStatusError::Client(e),
+    };
+}
+
+/// `parse_server_error()` recovers the [`NutError`] of an `ERR` response, which `rups` reports as a generic "Server error: <code> ..." for most codes
+fn parse_server_error(error: ClientError) -> ClientError {
+    let ClientError::Nut(NutError::Generic(message)) = &error else {
+        return error;
+    };
+
+    let code = message
+        .strip_prefix("Server error: ")
+        .and_then(|rest| rest.split_whitespace().next());
+
+    let nut_error = match code {
+        Some("VAR-NOT-SUPPORTED") => NutError::VarNotSupported,
+        Some("DRIVER-NOT-CONNECTED") => NutError::DriverNotConnected,
+        Some("DATA-STALE") => NutError::DataStale,
+        Some("INVALID-USERNAME") => NutError::InvalidUsername,
+        Some("INVALID-PASSWORD") => NutError::InvalidPassword,
+        Some("USERNAME-REQUIRED") => NutError::UsernameRequired,
+        Some("PASSWORD-REQUIRED") => NutError::PasswordRequired,
+        _ => return error,
+    };
+
+    return ClientError::Nut(nut_error);
+}
+
+/// `breaks_session()` returns true if `error` means the session with the NUT server can't be used anymore, rather than the request failing
+fn breaks_session(error: &ClientError) -> bool {
+    return match error {
+        ClientError::Io(_) => true,
+        ClientError::Nut(e) => !matches!(
+            e,
+            NutError::DriverNotConnected
+                | NutError::DataStale
+                | NutError::UnknownUps
+                | NutError::VarNotSupported
+        ),
+    };
+}
+
+/// `fetch_vars()` fetches each of `names` of the UPS `ups_name` with `GET VAR`, leaving out the variables the UPS doesn't support
+fn fetch_vars(
+    connection: &mut Connection,
     ups_name: &str,
-    host: &str,
-    username: &str,
-    password: &str,
-) -> Result<UPSStatus, Box<dyn std::error::Error>> {
-    let rsups_config = ConfigBuilder::new()
-        .with_host((host.to_string(), 3493).try_into().unwrap_or_default())
-        .with_auth(Some(Auth::new(
-            username.to_string(),
-            Some(password.to_string()),
-        )))
-        .with_debug(false)
-        .build();
-
-    let mut connection = Connection::new(&rsups_config)?;
-
-    let mut is_on_battery = false;
-    let mut battery_percentage = 100;
-    let mut load_percentage = 0;
-
-    for var in connection.list_vars(ups_name)? {
-        if var.name() == "ups.status" {
-            is_on_battery = !var.value().contains("OL");
-        } else if var.name() == "battery.charge" {
-            battery_percentage = var.value().parse::<u8>().unwrap();
-        } else if var.name() == "ups.load" {
-            load_percentage = var.value().parse::<u8>().unwrap();
-        }
-    }
-
-    return Ok(UPSStatus {
-        currently_on_battery: is_on_battery,
-        battery_percentage: battery_percentage,
-        load_percentage: load_percentage,
-    });
+    names: &[&str],
+) -> Result<HashMap<String, String>, ClientError> {
+    let mut vars: HashMap<String, String> = HashMap::new();
+
+    for name in names {
+        match connection
+            .get_var(ups_name, name)
+            .map_err(parse_server_error)
+        {
+            Ok(var) => {
+                vars.insert(name.to_string(), var.value());
+            }
+            Err(ClientError::Nut(NutError::VarNotSupported)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(vars);
+}
+
+/// `wait_for_response()` waits up to [`NUT_REQUEST_TIMEOUT`] for the worker of a [`NutSession`] to respond, treating no response as an I/O error
+fn wait_for_response<T>(responses: &Receiver<Result<T, ClientError>>) -> Result<T, ClientError> {
+    return match responses.recv_timeout(NUT_REQUEST_TIMEOUT) {
+        Ok(response) => response,
+        Err(RecvTimeoutError::Timeout) => Err(ClientError::Io(std::io::Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "NUT server didn't respond within {}s",
+                NUT_REQUEST_TIMEOUT.as_secs()
+            ),
+        ))),
+        Err(RecvTimeoutError::Disconnected) => Err(ClientError::Io(std::io::Error::new(
+            ErrorKind::BrokenPipe,
+            "NUT session closed unexpectedly",
+        ))),
+    };
+}
+
+/// A session with a NUT server, run on a worker thread so a server that stops responding can't block the caller for longer than [`NUT_REQUEST_TIMEOUT`]
+///
+/// Dropping the session logs out and closes the connection. A worker stuck on an unresponsive server is left to exit once TCP gives up.
+struct NutSession {
+    requests: Sender<Vec<&'static str>>,
+    responses: Receiver<Result<HashMap<String, String>, ClientError>>,
+}
+
+impl NutSession {
+    /// `NutSession::open()` connects and logs in to the NUT server in `config`, for querying the UPS `ups_name`
+    fn open(config: Config, ups_name: String) -> Result<Self, ClientError> {
+        let (requests, worker_requests) = channel::<Vec<&'static str>>();
+        let (worker_responses, responses) = channel();
+
+        spawn(move || {
+            let mut connection = match Connection::new(&config).map_err(parse_server_error) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let _ = worker_responses.send(Err(e));
+                    return;
+                }
+            };
+
+            if worker_responses.send(Ok(HashMap::new())).is_err() {
+                let _ = connection.close();
+                return;
+            }
+
+            // Runs until the session is dropped
+            for names in worker_requests {
+                let response = fetch_vars(&mut connection, &ups_name, &names);
+                if worker_responses.send(response).is_err() {
+                    break;
+                }
+            }
+
+            let _ = connection.close();
+        });
+
+        wait_for_response(&responses)?;
+
+        return Ok(NutSession {
+            requests,
+            responses,
+        });
+    }
+
+    /// `get_vars()` fetches each of `names` with `GET VAR`, leaving out the variables the UPS doesn't support
+    fn get_vars(&self, names: &[&'static str]) -> Result<HashMap<String, String>, ClientError> {
+        if self.requests.send(names.to_vec()).is_err() {
+            return Err(ClientError::Io(std::io::Error::new(
+                ErrorKind::BrokenPipe,
+                "NUT session closed unexpectedly",
+            )));
+        }
+
+        return wait_for_response(&self.responses);
+    }
+}
+
+/// A long-lived session with the NUT server of a UPS, which reconnects (and logs in again) with exponential backoff whenever the session is lost
+pub struct NutClient {
+    ups: UpsConfig,
+    session: Option<NutSession>,
+    /// Time to wait after the next failed connection attempt
+    backoff: Duration,
+    /// When the next connection attempt may be made, `None` if it may be made right away
+    retry_at: Option<Instant>,
+    /// When the NUT server stopped being reachable, `None` while it is reachable
+    unreachable_since: Option<SystemTime>,
+    /// When the UPS stopped reporting (see [`UPSStatusError::UpsUnavailable`]), `None` while it is reporting
+    unavailable_since: Option<SystemTime>,
+}
+
+impl NutClient {
+    pub fn new(ups: &UpsConfig) -> Self {
+        return NutClient {
+            ups: ups.clone(),
+            session: None,
+            backoff: MIN_RECONNECT_BACKOFF,
+            retry_at: None,
+            unreachable_since: None,
+            unavailable_since: None,
+        };
+    }
+
+    /// `ups()` returns the configuration of the UPS the client queries
+    pub fn ups(&self) -> &UpsConfig {
+        return &self.ups;
+    }
+
+    /// `unreachable_since()` returns when the NUT server stopped accepting connections, or `None` if it was reachable the last time it was connected to
+    pub fn unreachable_since(&self) -> Option<SystemTime> {
+        return self.unreachable_since;
+    }
+
+    /// `unavailable_since()` returns when the NUT server started reporting that the UPS isn't reporting, or `None` if it was reporting the last time it was queried
+    pub fn unavailable_since(&self) -> Option<SystemTime> {
+        return self.unavailable_since;
+    }
+
+    /// `connect()` returns the open session, connecting and logging in if there isn't one and the backoff has elapsed
+    fn connect(&mut self) -> Result<&NutSession, UPSStatusError> {
+        if self.session.is_none() {
+            let now = Instant::now();
+            if let Some(retry_at) = self.retry_at
+                && now < retry_at
+            {
+                return Err(UPSStatusError::Reconnecting(retry_at - now));
+            }
+
+            let auth = self
+                .ups
+                .username
+                .as_ref()
+                .map(|username| Auth::new(username.clone(), self.ups.password.clone()));
+            let authenticated = auth.is_some();
+
+            let rsups_config = ConfigBuilder::new()
+                .with_host((self.ups.host.clone(), 3493).try_into().unwrap_or_default())
+                .with_auth(auth)
+                .with_timeout(NUT_CONNECT_TIMEOUT)
+                .with_debug(false)
+                .build();
+
+            match NutSession::open(rsups_config, self.ups.ups_name.clone()) {
+                Ok(session) => {
+                    debug!(target: "UPS", "Connected to the NUT server at {}", self.ups.host);
+                    self.session = Some(session);
+                    self.backoff = MIN_RECONNECT_BACKOFF;
+                    self.retry_at = None;
+                    self.unreachable_since = None;
+                }
+                Err(e) => {
+                    // Errors from the NUT server itself (such as rejected credentials) mean it is still reachable
+                    if matches!(e, ClientError::Io(_)) {
+                        self.unreachable_since.get_or_insert_with(SystemTime::now);
+                    } else {
+                        self.unreachable_since = None;
+                    }
+
+                    debug!(target: "UPS", "Retrying the NUT server at {} in {}s", self.ups.host, self.backoff.as_secs());
+                    self.retry_at = Some(now + self.backoff);
+                    self.backoff = (self.backoff * 2).min(MAX_RECONNECT_BACKOFF);
+
+                    return Err(classify_client_error(e, authenticated));
+                }
+            }
+        }
+
+        return Ok(self.session.as_ref().unwrap());
+    }
+
+    /// `get_vars()` fetches each of `names` with `GET VAR` through the open session, leaving out the variables the UPS doesn't support
+    fn get_vars(
+        &mut self,
+        names: &[&'static str],
+    ) -> Result<HashMap<String, String>, UPSStatusError> {
+        let authenticated = self.ups.username.is_some();
+
+        return match self.connect()?.get_vars(names) {
+            Ok(vars) => {
+                self.unavailable_since = None;
+                Ok(vars)
+            }
+            Err(e) => {
+                if breaks_session(&e) {
+                    self.session = None;
+                }
+
+                // A server that stopped responding (e.g. it lost power) counts as unreachable until a new session is opened
+                if matches!(e, ClientError::Io(_)) {
+                    self.unreachable_since.get_or_insert_with(SystemTime::now);
+                }
+
+                let e = classify_client_error(e, authenticated);
+                if let UPSStatusError::UpsUnavailable(_) = e {
+                    self.unavailable_since.get_or_insert_with(SystemTime::now);
+                }
+
+                Err(e)
+            }
+        };
+    }
+
+    /// The `get_ups_status` function queries specific information (see [`UPSStatus`]) from the NUT server of the UPS
+    ///
+    /// If the session was lost since the last query, it reconnects and logs in again straight away.
+    pub fn get_ups_status(&mut self) -> Result<UPSStatus, UPSStatusError> {
+        let had_session = self.session.is_some();
+
+        let vars = match self.get_vars(&UPS_STATUS_VARS) {
+            Err(e) if had_session && self.session.is_none() => {
+                debug!(target: "UPS", "Lost the session with the NUT server at {} ({e}), reconnecting", self.ups.host);
+                self.get_vars(&UPS_STATUS_VARS)?
+            }
+            result => result?,
+        };
+
+        let status = vars.get("ups.status");
+        let flags = status
+            .map(|status| UpsFlags::parse(status))
+            .unwrap_or_default();
+
+        // Without a power state, the UPS can't be told apart from one on mains power
+        if !flags.has_power_state() {
+            return Err(UPSStatusError::UnknownStatus(status.cloned()));
+        }
+
+        // Values that are missing or can't be parsed are unknown, rather than given a default
+        let number = |name: &str| {
+            let value = vars.get(name)?;
+
+            return match value.trim().parse::<f64>() {
+                Ok(number) if number.is_finite() => Some(number),
+                _ => {
+                    warn!(target: "UPS", "{} | Ignoring {name} since '{value}' is not a number", self.ups.name);
+                    None
+                }
+            };
+        };
+
+        return Ok(UPSStatus {
+            currently_on_battery: flags.lost_power(),
+            flags,
+            battery_percentage: number("battery.charge"),
+            load_percentage: number("ups.load"),
+            battery_runtime: number("battery.runtime"),
+            battery_voltage: number("battery.voltage"),
+            input_voltage: number("input.voltage"),
+            output_voltage: number("output.voltage"),
+            real_power: number("ups.realpower"),
+            temperature: number("ups.temperature"),
+            model: vars.get("ups.model").cloned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_every_known_flag_and_ignores_unknown_ones() {
+        let flags = UpsFlags::parse(" OL  CHRG\tWHATEVER LB ");
+
+        assert_eq!(
+            flags.iter().collect::<Vec<UpsFlag>>(),
+            vec![UpsFlag::Online, UpsFlag::Charging, UpsFlag::LowBattery]
+        );
+    }
+
+    #[test]
+    fn parse_ignores_repeated_flags() {
+        let flags = UpsFlags::parse("OB OB DISCHRG");
+
+        assert_eq!(flags.iter().count(), 2);
+    }
+
+    #[test]
+    fn empty_status_has_no_power_state() {
+        let flags = UpsFlags::parse("");
+
+        assert!(!flags.has_power_state());
+        assert!(!flags.lost_power());
+        assert!(!flags.shutting_down());
+        assert_eq!(flags.to_string(), "UNKNOWN");
+    }
+
+    #[test]
+    fn status_without_ol_or_ob_has_no_power_state() {
+        assert!(!UpsFlags::parse("CHRG HB").has_power_state());
+        assert!(UpsFlags::parse("OL CHRG").has_power_state());
+        assert!(UpsFlags::parse("OB DISCHRG").has_power_state());
+    }
+
+    #[test]
+    fn online_has_not_lost_power() {
+        let flags = UpsFlags::parse("OL");
+
+        assert!(!flags.lost_power());
+        assert!(!flags.shutting_down());
+    }
+
+    #[test]
+    fn on_battery_has_lost_power() {
+        let flags = UpsFlags::parse("OB DISCHRG");
+
+        assert!(flags.lost_power());
+        assert!(!flags.shutting_down());
+    }
+
+    #[test]
+    fn calibration_on_battery_has_not_lost_power() {
+        let flags = UpsFlags::parse("OB CAL");
+
+        assert!(!flags.lost_power());
+        assert!(!flags.shutting_down());
+    }
+
+    #[test]
+    fn low_battery_only_shuts_down_once_power_is_lost() {
+        assert!(UpsFlags::parse("OB LB").shutting_down());
+        assert!(!UpsFlags::parse("OL LB").shutting_down());
+        assert!(!UpsFlags::parse("OB CAL LB").shutting_down());
+    }
+
+    #[test]
+    fn forced_shutdown_has_lost_power_even_on_mains() {
+        let flags = UpsFlags::parse("OL FSD");
+
+        assert!(flags.has_power_state());
+        assert!(flags.lost_power());
+        assert!(flags.shutting_down());
+    }
+
+    #[test]
+    fn output_off_has_lost_power() {
+        let flags = UpsFlags::parse("OFF");
+
+        assert!(flags.has_power_state());
+        assert!(flags.lost_power());
+        assert!(flags.shutting_down());
+    }
+
+    #[test]
+    fn bypass_counts_as_mains_power() {
+        let flags = UpsFlags::parse("OL BYPASS");
+
+        assert!(!flags.lost_power());
+        assert!(!flags.shutting_down());
+    }
 }
@@ -0,0 +1,301 @@
+use log::warn;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind, Result};
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::mac::MacAddress;
+
+const PROC_NET_ARP: &str = "/proc/net/arp";
+
+/// `ATF_COM` from `<net/if_arp.h>`: the entry has a resolved hardware address
+const ATF_COM: u32 = 0x02;
+
+/// `NLMSG_ALIGNTO` from `<linux/netlink.h>`
+const NETLINK_ALIGN: usize = 4;
+
+/// The size of `struct ndmsg` from `<linux/neighbour.h>`
+const NDMSG_LENGTH: usize = 12;
+
+/// The size of `struct rtattr` from `<linux/rtnetlink.h>`
+const RTATTR_LENGTH: usize = 4;
+
+/// A single entry in the kernel's neighbor (ARP/NDP) table
+#[derive(Debug, Clone)]
+pub struct NeighborEntry {
+    pub ip: IpAddr,
+    /// The hardware address of the neighbor, or `None` if it hasn't been resolved (yet)
+    pub mac_address: Option<MacAddress>,
+}
+
+/// The request sent to the kernel to dump the neighbor table, `struct nlmsghdr` followed by `struct ndmsg`
+#[repr(C)]
+struct NeighborDumpRequest {
+    header: libc::nlmsghdr,
+    family: u8,
+    pad1: u8,
+    pad2: u16,
+    ifindex: i32,
+    state: u16,
+    flags: u8,
+    kind: u8,
+}
+
+/// `parse_hardware_address()` parses the octets of an Ethernet address, returning `None` for any other length or an all-zero address
+fn parse_hardware_address(octets: &[u8]) -> Option<MacAddress> {
+    if octets.iter().all(|octet| *octet == 0) {
+        return None;
+    }
+
+    return MacAddress::from_octets(octets);
+}
+
+/// `netlink_align()` rounds `length` up to the alignment of netlink messages and attributes
+fn netlink_align(length: usize) -> usize {
+    return (length + NETLINK_ALIGN - 1) & !(NETLINK_ALIGN - 1);
+}
+
+/// `read_u16()` reads a native-endian `u16` at `offset`
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    return u16::from_ne_bytes([bytes[offset], bytes[offset + 1]]);
+}
+
+/// `read_u32()` reads a native-endian `u32` at `offset`
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap());
+}
+
+/// `read_arp_table()` reads the IPv4 neighbor table from `/proc/net/arp`
+///
+/// Each line after the header looks like `IP address  HW type  Flags  HW address  Mask  Device`
+fn read_arp_table() -> Result<Vec<NeighborEntry>> {
+    let contents = read_to_string(PROC_NET_ARP)?;
+    let mut entries: Vec<NeighborEntry> = Vec::new();
+
+    for line in contents.lines().skip(1) {
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        if columns.len() < 4 {
+            continue;
+        }
+
+        let Ok(ip) = columns[0].parse::<Ipv4Addr>() else {
+            continue;
+        };
+        let flags = u32::from_str_radix(columns[2].trim_start_matches("0x"), 16).unwrap_or(0);
+
+        let mac_address = if flags & ATF_COM != 0 {
+            columns[3]
+                .parse::<MacAddress>()
+                .ok()
+                .and_then(|mac_address| parse_hardware_address(&mac_address.octets()))
+        } else {
+            None
+        };
+
+        entries.push(NeighborEntry {
+            ip: IpAddr::V4(ip),
+            mac_address,
+        });
+    }
+
+    return Ok(entries);
+}
+
+/// `open_netlink_socket()` opens a `NETLINK_ROUTE` socket to talk to the kernel's routing subsystem
+fn open_netlink_socket() -> Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    return Ok(unsafe { OwnedFd::from_raw_fd(fd) });
+}
+
+/// `request_neighbor_dump()` asks the kernel for every neighbor of the address `family` (e.g. `AF_INET6`)
+fn request_neighbor_dump(socket: &OwnedFd, family: i32) -> Result<()> {
+    let request = NeighborDumpRequest {
+        header: libc::nlmsghdr {
+            nlmsg_len: size_of::<NeighborDumpRequest>() as u32,
+            nlmsg_type: libc::RTM_GETNEIGH,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16,
+            nlmsg_seq: 1,
+            nlmsg_pid: 0,
+        },
+        family: family as u8,
+        pad1: 0,
+        pad2: 0,
+        ifindex: 0,
+        state: 0,
+        flags: 0,
+        kind: 0,
+    };
+
+    let mut kernel: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    kernel.nl_family = libc::AF_NETLINK as u16;
+
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            &request as *const NeighborDumpRequest as *const libc::c_void,
+            size_of::<NeighborDumpRequest>(),
+            0,
+            &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+            size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    return Ok(());
+}
+
+/// `parse_neighbor_message()` parses the body of a `RTM_NEWNEIGH` message (`struct ndmsg` followed by its attributes)
+fn parse_neighbor_message(body: &[u8]) -> Option<NeighborEntry> {
+    if body.len() < NDMSG_LENGTH {
+        return None;
+    }
+
+    let family = body[0] as i32;
+    let state = read_u16(body, 8);
+
+    let mut ip: Option<IpAddr> = None;
+    let mut mac_address: Option<MacAddress> = None;
+
+    let mut offset = NDMSG_LENGTH;
+    while offset + RTATTR_LENGTH <= body.len() {
+        let length = read_u16(body, offset) as usize;
+        let kind = read_u16(body, offset + 2);
+
+        if length < RTATTR_LENGTH || offset + length > body.len() {
+            break;
+        }
+
+        let value = &body[offset + RTATTR_LENGTH..offset + length];
+        match kind {
+            libc::NDA_DST if family == libc::AF_INET6 && value.len() == 16 => {
+                let octets: [u8; 16] = value.try_into().unwrap();
+                ip = Some(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            libc::NDA_DST if family == libc::AF_INET && value.len() == 4 => {
+                let octets: [u8; 4] = value.try_into().unwrap();
+                ip = Some(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            libc::NDA_LLADDR => mac_address = parse_hardware_address(value),
+            _ => {}
+        }
+
+        offset += netlink_align(length);
+    }
+
+    // Entries the kernel gave up on, or is still resolving, have no usable address
+    if state & (libc::NUD_INCOMPLETE | libc::NUD_FAILED) != 0 {
+        mac_address = None;
+    }
+
+    return ip.map(|ip| NeighborEntry { ip, mac_address });
+}
+
+/// `read_netlink_neighbors()` dumps the neighbor table of the address `family` (e.g. `AF_INET6`) over netlink
+fn read_netlink_neighbors(family: i32) -> Result<Vec<NeighborEntry>> {
+    let socket = open_netlink_socket()?;
+    request_neighbor_dump(&socket, family)?;
+
+    let mut entries: Vec<NeighborEntry> = Vec::new();
+    let mut buffer = vec![0u8; 32 * 1024];
+
+    loop {
+        let received = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+
+        if received < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let messages = &buffer[..received as usize];
+        let header_length = size_of::<libc::nlmsghdr>();
+        let mut offset = 0;
+
+        while offset + header_length <= messages.len() {
+            let length = read_u32(messages, offset) as usize;
+            let kind = read_u16(messages, offset + 4) as i32;
+
+            if length < header_length || offset + length > messages.len() {
+                break;
+            }
+
+            match kind {
+                libc::NLMSG_DONE => return Ok(entries),
+                libc::NLMSG_ERROR => {
+                    let code = i32::from_ne_bytes(
+                        messages[offset + header_length..offset + header_length + 4]
+                            .try_into()
+                            .unwrap(),
+                    );
+
+                    if code != 0 {
+                        return Err(Error::from_raw_os_error(-code));
+                    }
+                }
+                kind if kind == libc::RTM_NEWNEIGH as i32 => {
+                    if let Some(entry) =
+                        parse_neighbor_message(&messages[offset + header_length..offset + length])
+                    {
+                        entries.push(entry);
+                    }
+                }
+                _ => {}
+            }
+
+            offset += netlink_align(length);
+        }
+
+        if received == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Netlink socket closed before the neighbor table was received",
+            ));
+        }
+    }
+}
+
+/// `read_neighbor_table()` reads the kernel's neighbor table, `/proc/net/arp` for IPv4 and netlink for IPv6
+///
+/// The IPv4 entries are still returned (with a warning) if the IPv6 entries can't be read, such as when netlink sockets aren't allowed
+pub fn read_neighbor_table() -> Result<Vec<NeighborEntry>> {
+    let mut entries = read_arp_table()?;
+
+    match read_netlink_neighbors(libc::AF_INET6) {
+        Ok(mut ipv6_entries) => entries.append(&mut ipv6_entries),
+        Err(e) => {
+            warn!("Unable to read the IPv6 neighbor table, only IPv4 neighbors are used: {e}")
+        }
+    }
+
+    return Ok(entries);
+}
+
+/// `find_neighbor()` looks up the entry for `ip` in the kernel's neighbor table, returning `None` if there isn't one
+pub fn find_neighbor(ip: IpAddr) -> Result<Option<NeighborEntry>> {
+    let entries = match ip {
+        IpAddr::V4(_) => read_arp_table()?,
+        IpAddr::V6(_) => read_netlink_neighbors(libc::AF_INET6)?,
+    };
+
+    return Ok(entries.into_iter().find(|entry| entry.ip == ip));
+}
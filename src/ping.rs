@@ -0,0 +1,236 @@
+use std::io::Result;
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::fd::FromRawFd;
+#[cfg(not(unix))]
+use std::process::{Command, Stdio};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicU16, Ordering};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+use crate::config::PingConfig;
+
+/// Payload sent with every echo request
+#[cfg(unix)]
+const PAYLOAD: &[u8] = b"nutjob";
+
+/// Size of the buffer echo replies are read into, large enough for an IPv4 header and any ICMP message
+#[cfg(unix)]
+const RECEIVE_BUFFER_SIZE: usize = 1500;
+
+/// Counter mixed into the identifier of each ping, so concurrent pings through raw sockets can tell their replies apart
+#[cfg(unix)]
+static NEXT_IDENTIFIER: AtomicU16 = AtomicU16::new(0);
+
+/// The type of socket that echo requests are sent through
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SocketKind {
+    /// Unprivileged ICMP socket, which the kernel filters replies for
+    Datagram,
+    /// Raw socket, which needs root or `CAP_NET_RAW` and receives every ICMP message
+    Raw,
+}
+
+/// `open_icmp_socket()` opens an ICMP socket for pinging `ip`, preferring an unprivileged datagram socket and falling back to a raw socket
+///
+/// The socket is wrapped in a [`UdpSocket`] for its `connect`/`send`/`recv` methods, which work on any datagram socket.
+#[cfg(unix)]
+fn open_icmp_socket(ip: IpAddr) -> Result<(UdpSocket, SocketKind)> {
+    let (domain, protocol) = match ip {
+        IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+        IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+    };
+
+    let mut errors: Vec<std::io::Error> = Vec::new();
+
+    for (socket_type, kind) in [
+        (libc::SOCK_DGRAM, SocketKind::Datagram),
+        (libc::SOCK_RAW, SocketKind::Raw),
+    ] {
+        // The socket isn't inherited by the commands run for `command` checks
+        #[cfg(target_os = "linux")]
+        let socket_type = socket_type | libc::SOCK_CLOEXEC;
+
+        let fd = unsafe { libc::socket(domain, socket_type, protocol) };
+        if fd >= 0 {
+            #[cfg(not(target_os = "linux"))]
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC)
+            };
+
+            return Ok((unsafe { UdpSocket::from_raw_fd(fd) }, kind));
+        }
+
+        errors.push(std::io::Error::last_os_error());
+    }
+
+    return Err(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!(
+            "Unable to open an ICMP socket, allow unprivileged ICMP with the net.ipv4.ping_group_range sysctl or grant CAP_NET_RAW (datagram socket: {}, raw socket: {})",
+            errors[0], errors[1]
+        ),
+    ));
+}
+
+/// `internet_checksum()` computes the one's complement checksum used by ICMP
+#[cfg(unix)]
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    return !(sum as u16);
+}
+
+/// `echo_request()` builds an ICMP (or ICMPv6) echo request to `ip`
+#[cfg(unix)]
+fn echo_request(ip: IpAddr, identifier: u16, sequence: u16) -> Vec<u8> {
+    let message_type = match ip {
+        IpAddr::V4(_) => 8,
+        IpAddr::V6(_) => 128,
+    };
+
+    let mut packet = vec![message_type, 0, 0, 0];
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(PAYLOAD);
+
+    // The kernel fills in the ICMPv6 checksum, since it covers the IPv6 pseudo-header
+    if ip.is_ipv4() {
+        let checksum = internet_checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    return packet;
+}
+
+/// `is_echo_reply()` returns true if `packet` is the reply to the echo request with `identifier` and `sequence`
+#[cfg(unix)]
+fn is_echo_reply(
+    ip: IpAddr,
+    kind: SocketKind,
+    packet: &[u8],
+    identifier: u16,
+    sequence: u16,
+) -> bool {
+    let (packet, reply_type) = match ip {
+        // Raw sockets (and datagram sockets on some platforms) include the IPv4 header, which never starts with a zero byte like an echo reply
+        IpAddr::V4(_) if packet.first().is_some_and(|byte| byte >> 4 == 4) => {
+            let header_length = usize::from(packet[0] & 0x0f) * 4;
+            (packet.get(header_length..).unwrap_or_default(), 0)
+        }
+        IpAddr::V4(_) => (packet, 0),
+        IpAddr::V6(_) => (packet, 129),
+    };
+
+    if packet.len() < 8 || packet[0] != reply_type {
+        return false;
+    }
+
+    // The kernel replaces the identifier of datagram sockets (and only passes them their own replies)
+    let identifier_matches =
+        kind == SocketKind::Datagram || packet[4..6] == identifier.to_be_bytes();
+
+    return identifier_matches && packet[6..8] == sequence.to_be_bytes();
+}
+
+/// `send_echo()` sends a single echo request through `socket`, returning true if it was answered within `timeout`
+///
+/// Errors while sending or receiving (such as the host being unreachable) count as no reply.
+#[cfg(unix)]
+fn send_echo(
+    socket: &UdpSocket,
+    ip: IpAddr,
+    kind: SocketKind,
+    identifier: u16,
+    sequence: u16,
+    timeout: Duration,
+) -> bool {
+    if socket
+        .send(&echo_request(ip, identifier, sequence))
+        .is_err()
+    {
+        return false;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; RECEIVE_BUFFER_SIZE];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+            return false;
+        }
+
+        match socket.recv(&mut buffer) {
+            Ok(length) => {
+                if is_echo_reply(ip, kind, &buffer[..length], identifier, sequence) {
+                    return true;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+/// `ping()` sends up to `config.count` echo requests to `ip` one after another, returning true once `config.required_successes` of them are answered
+///
+/// It only returns an error if no ICMP socket could be opened.
+#[cfg(unix)]
+pub fn ping(ip: IpAddr, config: &PingConfig) -> Result<bool> {
+    let (socket, kind) = open_icmp_socket(ip)?;
+
+    // Connecting limits the replies received to those from `ip`
+    if socket.connect(SocketAddr::new(ip, 0)).is_err() {
+        return Ok(false);
+    }
+
+    let identifier = (std::process::id() as u16) ^ NEXT_IDENTIFIER.fetch_add(1, Ordering::Relaxed);
+    let timeout = Duration::from_millis(config.timeout.into());
+
+    let mut successes: u8 = 0;
+    for sequence in 0..config.count {
+        // Stop early once the result is certain
+        let remaining = config.count - sequence;
+        if successes >= config.required_successes
+            || successes + remaining < config.required_successes
+        {
+            break;
+        }
+
+        if send_echo(&socket, ip, kind, identifier, sequence.into(), timeout) {
+            successes += 1;
+        }
+    }
+
+    return Ok(successes >= config.required_successes);
+}
+
+/// `ping()` pings `ip` with the system `ping` command, returning true if it succeeded
+///
+/// `config.required_successes` isn't supported, a single reply is enough.
+#[cfg(not(unix))]
+pub fn ping(ip: IpAddr, config: &PingConfig) -> Result<bool> {
+    let status = Command::new("ping")
+        .arg("-n")
+        .arg(config.count.to_string())
+        .arg("-w")
+        .arg(config.timeout.to_string())
+        .arg(ip.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    return Ok(status.success());
+}
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::config::{DeviceConfig, PowerPolicy};
+use crate::monitoring::{UPSStatus, UpsFlag, UpsFlags};
+
+/// The latest known state of a single UPS
+#[derive(Debug, Clone)]
+pub enum FeedStatus {
+    /// The NUT server is reporting the status of the UPS
+    Reporting(UPSStatus),
+    /// The NUT server is reachable, but the UPS is not reporting (e.g. it has run out of battery and shut off)
+    Dead,
+    /// The NUT server has been unreachable for longer than the grace period (e.g. it is powered by the UPS), so the UPS is assumed to have lost power at `since`
+    Unreachable { since: SystemTime },
+}
+
+/// The set of UPSes powering a device, along with the policy deciding when the device has lost power
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PowerFeeds {
+    pub ups: Vec<String>,
+    pub policy: PowerPolicy,
+}
+
+impl PowerFeeds {
+    /// `PowerFeeds::of()` returns the power feeds of `device`
+    ///
+    /// Devices powered by the same UPSes with the same policy share a single outage/restoration
+    pub fn of(device: &DeviceConfig) -> Self {
+        let mut ups = device.ups.clone();
+        ups.sort();
+        ups.dedup();
+
+        // The policy makes no difference with a single UPS
+        let policy = if ups.len() > 1 {
+            device.power_policy
+        } else {
+            PowerPolicy::default()
+        };
+
+        return PowerFeeds { ups, policy };
+    }
+
+    /// `status()` combines the status of every UPS into the status of the feeds as a whole
+    ///
+    /// The feeds are on battery once any (or all, depending on the policy) of the UPSes are on battery, dead or unreachable.
+    /// The battery and load percentages are the most pessimistic known values of the UPSes on AC power, or of every reporting UPS if none are.
+    /// The flags are those of the UPSes deciding the outcome, with dead or unreachable UPSes counting as `OFF`.
+    ///
+    /// It returns `None` when the outcome can't be decided because the status of a UPS is unknown.
+    pub fn status(&self, statuses: &BTreeMap<String, FeedStatus>) -> Option<UPSStatus> {
+        let mut reporting: Vec<&UPSStatus> = Vec::new();
+        let mut dead = 0;
+        let mut unknown = 0;
+
+        for ups in &self.ups {
+            match statuses.get(ups) {
+                Some(FeedStatus::Reporting(ups_status)) => reporting.push(ups_status),
+                Some(FeedStatus::Dead | FeedStatus::Unreachable { .. }) => dead += 1,
+                None => unknown += 1,
+            }
+        }
+
+        let on_ac = reporting
+            .iter()
+            .filter(|ups_status| !ups_status.currently_on_battery)
+            .count();
+        let lost_power = reporting.len() - on_ac + dead;
+
+        let currently_on_battery = match self.policy {
+            PowerPolicy::Any if lost_power > 0 => true,
+            PowerPolicy::All if on_ac > 0 => false,
+            _ if unknown > 0 => return None,
+            PowerPolicy::Any => false,
+            PowerPolicy::All => true,
+        };
+
+        let relevant: Vec<&&UPSStatus> = if on_ac > 0 {
+            reporting
+                .iter()
+                .filter(|ups_status| !ups_status.currently_on_battery)
+                .collect()
+        } else {
+            reporting.iter().collect()
+        };
+
+        let mut flags = UpsFlags::default();
+        for ups_status in reporting
+            .iter()
+            .filter(|ups_status| ups_status.currently_on_battery == currently_on_battery)
+        {
+            for flag in ups_status.flags.iter() {
+                flags.insert(flag);
+            }
+        }
+        if currently_on_battery && dead > 0 {
+            flags.insert(UpsFlag::Off);
+        }
+
+        return Some(UPSStatus {
+            currently_on_battery,
+            flags,
+            battery_percentage: relevant
+                .iter()
+                .filter_map(|ups_status| ups_status.battery_percentage)
+                .reduce(f64::min),
+            load_percentage: relevant
+                .iter()
+                .filter_map(|ups_status| ups_status.load_percentage)
+                .reduce(f64::max),
+            ..Default::default()
+        });
+    }
+
+    /// `lost_contact_at()` returns the earliest time the NUT server of any of the UPSes became unreachable, if any are assumed to have lost power because of it
+    pub fn lost_contact_at(&self, statuses: &BTreeMap<String, FeedStatus>) -> Option<SystemTime> {
+        return self
+            .ups
+            .iter()
+            .filter_map(|ups| match statuses.get(ups) {
+                Some(FeedStatus::Unreachable { since }) => Some(*since),
+                _ => None,
+            })
+            .min();
+    }
+}
+
+impl fmt::Display for PowerFeeds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = match self.policy {
+            PowerPolicy::Any => " | ",
+            PowerPolicy::All => " & ",
+        };
+
+        write!(f, "{}", self.ups.join(separator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feeds(policy: PowerPolicy) -> PowerFeeds {
+        return PowerFeeds {
+            ups: vec![String::from("a"), String::from("b")],
+            policy,
+        };
+    }
+
+    fn reporting(status: &str, battery_percentage: f64, load_percentage: f64) -> FeedStatus {
+        let flags = UpsFlags::parse(status);
+
+        return FeedStatus::Reporting(UPSStatus {
+            currently_on_battery: flags.lost_power(),
+            flags,
+            battery_percentage: Some(battery_percentage),
+            load_percentage: Some(load_percentage),
+            ..Default::default()
+        });
+    }
+
+    fn statuses(a: Option<FeedStatus>, b: Option<FeedStatus>) -> BTreeMap<String, FeedStatus> {
+        let mut statuses = BTreeMap::new();
+        if let Some(a) = a {
+            statuses.insert(String::from("a"), a);
+        }
+        if let Some(b) = b {
+            statuses.insert(String::from("b"), b);
+        }
+
+        return statuses;
+    }
+
+    fn unreachable() -> FeedStatus {
+        return FeedStatus::Unreachable {
+            since: SystemTime::UNIX_EPOCH,
+        };
+    }
+
+    #[test]
+    fn any_loses_power_once_one_ups_is_on_battery() {
+        let statuses = statuses(
+            Some(reporting("OL", 100.0, 20.0)),
+            Some(reporting("OB", 80.0, 30.0)),
+        );
+
+        let status = feeds(PowerPolicy::Any).status(&statuses).unwrap();
+
+        assert!(status.currently_on_battery);
+        assert!(status.flags.contains(UpsFlag::OnBattery));
+        assert!(!status.flags.contains(UpsFlag::Online));
+    }
+
+    #[test]
+    fn all_keeps_power_while_one_ups_is_on_mains() {
+        let statuses = statuses(
+            Some(reporting("OL", 100.0, 20.0)),
+            Some(reporting("OB", 80.0, 30.0)),
+        );
+
+        let status = feeds(PowerPolicy::All).status(&statuses).unwrap();
+
+        assert!(!status.currently_on_battery);
+        assert!(status.flags.contains(UpsFlag::Online));
+        assert!(!status.flags.contains(UpsFlag::OnBattery));
+        // Only the UPSes still on mains power decide the percentages
+        assert_eq!(status.battery_percentage, Some(100.0));
+        assert_eq!(status.load_percentage, Some(20.0));
+    }
+
+    #[test]
+    fn all_loses_power_once_every_ups_is_on_battery() {
+        let statuses = statuses(
+            Some(reporting("OB", 60.0, 20.0)),
+            Some(reporting("OB LB", 10.0, 30.0)),
+        );
+
+        let status = feeds(PowerPolicy::All).status(&statuses).unwrap();
+
+        assert!(status.currently_on_battery);
+        assert!(status.flags.contains(UpsFlag::LowBattery));
+        // The most pessimistic values of every UPS
+        assert_eq!(status.battery_percentage, Some(10.0));
+        assert_eq!(status.load_percentage, Some(30.0));
+    }
+
+    #[test]
+    fn dead_ups_counts_as_off() {
+        let statuses = statuses(Some(reporting("OB", 60.0, 20.0)), Some(FeedStatus::Dead));
+
+        let status = feeds(PowerPolicy::All).status(&statuses).unwrap();
+
+        assert!(status.currently_on_battery);
+        assert!(status.flags.contains(UpsFlag::Off));
+        assert_eq!(status.battery_percentage, Some(60.0));
+    }
+
+    #[test]
+    fn any_loses_power_once_one_ups_is_unreachable() {
+        let statuses = statuses(Some(reporting("OL", 100.0, 20.0)), Some(unreachable()));
+
+        let status = feeds(PowerPolicy::Any).status(&statuses).unwrap();
+
+        assert!(status.currently_on_battery);
+        assert!(status.flags.contains(UpsFlag::Off));
+    }
+
+    #[test]
+    fn all_keeps_power_with_a_dead_ups_while_one_is_on_mains() {
+        let statuses = statuses(Some(reporting("OL", 100.0, 20.0)), Some(FeedStatus::Dead));
+
+        let status = feeds(PowerPolicy::All).status(&statuses).unwrap();
+
+        assert!(!status.currently_on_battery);
+        assert!(!status.flags.contains(UpsFlag::Off));
+    }
+
+    #[test]
+    fn all_loses_power_when_every_ups_is_dead_or_unreachable() {
+        let statuses = statuses(Some(FeedStatus::Dead), Some(unreachable()));
+
+        let status = feeds(PowerPolicy::All).status(&statuses).unwrap();
+
+        assert!(status.currently_on_battery);
+        assert!(status.flags.contains(UpsFlag::Off));
+        assert_eq!(status.battery_percentage, None);
+    }
+
+    #[test]
+    fn unknown_ups_only_matters_when_it_could_change_the_outcome() {
+        // Already decided by the UPS on battery (any) or on mains (all)
+        let on_battery = statuses(Some(reporting("OB", 60.0, 20.0)), None);
+        let on_mains = statuses(Some(reporting("OL", 100.0, 20.0)), None);
+
+        assert!(
+            feeds(PowerPolicy::Any)
+                .status(&on_battery)
+                .unwrap()
+                .currently_on_battery
+        );
+        assert!(
+            !feeds(PowerPolicy::All)
+                .status(&on_mains)
+                .unwrap()
+                .currently_on_battery
+        );
+        assert!(feeds(PowerPolicy::Any).status(&on_mains).is_none());
+        assert!(feeds(PowerPolicy::All).status(&on_battery).is_none());
+    }
+
+    #[test]
+    fn lost_contact_at_is_the_earliest_unreachable_ups() {
+        let later = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(60);
+        let statuses = statuses(
+            Some(FeedStatus::Unreachable { since: later }),
+            Some(unreachable()),
+        );
+
+        assert_eq!(
+            feeds(PowerPolicy::All).lost_contact_at(&statuses),
+            Some(SystemTime::UNIX_EPOCH)
+        );
+        assert_eq!(
+            feeds(PowerPolicy::All).lost_contact_at(&BTreeMap::new()),
+            None
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::panic::resume_unwind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::scope;
+
+use crate::config::{DeviceConfig, MacSourceConfig, PingConfig};
+use crate::mac::find_device_address;
+use crate::monitoring::is_device_online;
+
+/// Maximum number of devices probed at once each polling cycle
+const PROBE_WORKERS: usize = 16;
+
+/// The result of probing a single device
+#[derive(Debug, Clone)]
+pub struct DeviceProbe {
+    /// The address the device was checked at, `None` if its current IP address couldn't be found
+    pub address: Option<String>,
+    /// Whether the device passed its check
+    pub passed: bool,
+    /// Whether the device is considered online, which only differs from `passed` once [`ProbeSnapshot::set_online`] debounces it
+    pub online: bool,
+}
+
+/// Whether every device responded during one polling cycle, shared by everything that needs to know if a device is online during that cycle
+#[derive(Debug, Clone, Default)]
+pub struct ProbeSnapshot {
+    devices: HashMap<String, DeviceProbe>,
+}
+
+impl ProbeSnapshot {
+    /// `is_online()` returns true if the device named `friendly_name` is considered online
+    pub fn is_online(&self, friendly_name: &str) -> bool {
+        return self
+            .devices
+            .get(friendly_name)
+            .is_some_and(|probe| probe.online);
+    }
+
+    /// `get()` returns the result of probing the device named `friendly_name`
+    pub fn get(&self, friendly_name: &str) -> Option<&DeviceProbe> {
+        return self.devices.get(friendly_name);
+    }
+
+    /// `set_online()` sets whether the device named `friendly_name` is considered online, once its check results have been debounced
+    pub fn set_online(&mut self, friendly_name: &str, online: bool) {
+        if let Some(probe) = self.devices.get_mut(friendly_name) {
+            probe.online = online;
+        }
+    }
+}
+
+/// `run_pool()` calls `probe` on every item of `items` using at most `workers` threads, returning the results in the same order as `items`
+pub fn run_pool<T, R>(items: &[T], workers: usize, probe: impl Fn(&T) -> R + Sync) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let next_item = AtomicUsize::new(0);
+
+    let mut results: Vec<(usize, R)> = scope(|scope| {
+        let handles: Vec<_> = (0..workers.clamp(1, items.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results: Vec<(usize, R)> = Vec::new();
+
+                    // Each worker takes the next item until none are left, so slow items don't hold up the rest
+                    loop {
+                        let index = next_item.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            return results;
+                        };
+
+                        results.push((index, probe(item)));
+                    }
+                })
+            })
+            .collect();
+
+        return handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|e| resume_unwind(e)))
+            .collect();
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+
+    return results.into_iter().map(|(_, result)| result).collect();
+}
+
+/// `probe_devices()` finds the current address of every device in `devices` and pings it with `ping_config`, several devices at a time
+pub fn probe_devices(
+    devices: &[DeviceConfig],
+    mac_sources: &[MacSourceConfig],
+    ping_config: &PingConfig,
+) -> ProbeSnapshot {
+    let probes = run_pool(devices, PROBE_WORKERS, |device| {
+        let address = find_device_address(device, mac_sources);
+        let passed = address
+            .as_deref()
+            .is_some_and(|address| is_device_online(address, &device.check, ping_config));
+
+        return DeviceProbe {
+            address,
+            passed,
+            online: passed,
+        };
+    });
+
+    return ProbeSnapshot {
+        devices: devices
+            .iter()
+            .map(|device| device.friendly_name.clone())
+            .zip(probes)
+            .collect(),
+    };
+}
@@ -0,0 +1,494 @@
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{DeviceConfig, NutjobConfig, WakeOnLanConfig, WakePolicy};
+use crate::dependencies::wake_stages;
+use crate::monitoring::{UPSStatus, format_value};
+use crate::probing::ProbeSnapshot;
+use crate::state::{
+    can_attempt_wake, count_waking_devices, get_cached_mac_address, get_last_wol_sent_at,
+    get_settled_at, mark_online_devices, mark_wol_attempted, reset_device_states,
+    was_device_online,
+};
+use crate::wakeonlan::wakeonlan;
+
+/// `get_wake_throttle()` checks whether waking the device named `friendly_name` now would exceed the staggering limits in `wol_config`
+///
+/// It returns the reason the wake should be held back, or `None` if the device can be woken
+fn get_wake_throttle(
+    wol_config: &WakeOnLanConfig,
+    ups_status: &UPSStatus,
+    friendly_name: &str,
+    unavailable_devices: &[String],
+) -> Option<String> {
+    // An unknown load can't be held against the ceiling
+    if let Some(max_load_percentage) = wol_config.max_load_percentage
+        && let Some(load_percentage) = ups_status.load_percentage
+        && load_percentage > f64::from(max_load_percentage)
+    {
+        return Some(format!(
+            "waiting for UPS load to drop ({load_percentage}%/{max_load_percentage}%)"
+        ));
+    }
+
+    if let Some(last_wol_sent_at) = get_last_wol_sent_at() {
+        let elapsed = last_wol_sent_at.elapsed().unwrap_or_default();
+        let wake_interval = Duration::from_secs(wol_config.wake_interval.into());
+
+        if elapsed < wake_interval {
+            return Some(format!(
+                "waiting {} more second(s) since the last WoL packet",
+                (wake_interval - elapsed).as_secs()
+            ));
+        }
+    }
+
+    if wol_config.max_concurrent_wakes > 0 {
+        // Devices that won't come online don't hold a slot, and a device being woken again doesn't need a new one
+        let mut excluded = unavailable_devices.to_vec();
+        excluded.push(friendly_name.to_string());
+
+        let waking_devices = count_waking_devices(&excluded);
+
+        if waking_devices >= wol_config.max_concurrent_wakes.into() {
+            return Some(format!(
+                "waiting for devices to come online ({waking_devices}/{} waking)",
+                wol_config.max_concurrent_wakes
+            ));
+        }
+    }
+
+    return None;
+}
+
+/// The `Restoration` struct tracks outages of a set of power feeds (see [`crate::power::PowerFeeds`]) and the restoration of the devices they power
+#[derive(Debug, Default)]
+pub struct Restoration {
+    awaiting_ac: bool,
+    restoring: bool,
+    restoration_started: Option<SystemTime>,
+    waking_started: bool,
+    restored_devices: Vec<String>,
+    failed_devices: Vec<String>,
+    /// Devices that were not woken because a device they depend on never came online
+    blocked_devices: Vec<String>,
+    skipped_devices: Vec<String>,
+    /// Devices that were not woken because their MAC address was never learned (see `mac_address: arp`)
+    unlearned_devices: Vec<String>,
+    /// When contact was lost with the NUT server of a UPS that is assumed to have lost power, `None` if none have
+    lost_contact_at: Option<SystemTime>,
+    /// Whether the devices were definitely shut down during the outage (see [`crate::monitoring::UpsFlags::shutting_down`])
+    devices_shut_down: bool,
+    /// When each dependency first passed a check during this restoration, tracked once the devices were shut down
+    dependencies_passed_at: BTreeMap<String, SystemTime>,
+}
+
+impl Restoration {
+    /// `Restoration::new()` starts tracking the power feeds named `name`, restoring their devices straight away if they were on battery when the service stopped
+    pub fn new(name: &str, was_on_battery: bool) -> Self {
+        if was_on_battery {
+            info!(target: "UPS", "Service is restoring from an outage of '{name}'");
+        }
+
+        // The service stopped during the outage, so devices only count as restored once they pass a check again
+        return Restoration {
+            restoring: was_on_battery,
+            devices_shut_down: was_on_battery,
+            ..Default::default()
+        };
+    }
+
+    /// `retain_devices()` stops tracking the restoration of devices for which `is_configured` returns false
+    pub fn retain_devices(&mut self, is_configured: impl Fn(&String) -> bool) {
+        self.restored_devices.retain(&is_configured);
+        self.failed_devices.retain(&is_configured);
+        self.blocked_devices.retain(&is_configured);
+        self.skipped_devices.retain(&is_configured);
+        self.unlearned_devices.retain(&is_configured);
+        self.dependencies_passed_at
+            .retain(|friendly_name, _| is_configured(friendly_name));
+    }
+
+    /// `set_lost_contact_at()` records when contact was lost with the NUT server of a UPS assumed to have lost power, so devices online before then are restored
+    pub fn set_lost_contact_at(&mut self, lost_contact_at: Option<SystemTime>) {
+        self.lost_contact_at = lost_contact_at;
+    }
+
+    /// `unavailable_devices()` returns the devices that won't be brought online by this restoration, so devices depending on them can be reported as blocked
+    pub fn unavailable_devices(&self) -> impl Iterator<Item = &String> {
+        return self
+            .failed_devices
+            .iter()
+            .chain(&self.blocked_devices)
+            .chain(&self.skipped_devices)
+            .chain(&self.unlearned_devices);
+    }
+
+    /// `clear_devices()` forgets the outcome of the last restoration for every device
+    fn clear_devices(&mut self) {
+        self.restored_devices.clear();
+        self.failed_devices.clear();
+        self.blocked_devices.clear();
+        self.skipped_devices.clear();
+        self.unlearned_devices.clear();
+    }
+
+    fn finish_restoration(&mut self, name: &str) {
+        if self.failed_devices.is_empty() {
+            info!("'{name}' on AC power and all devices restored!");
+        } else {
+            warn!(
+                "Some devices on '{}' failed to wake within the timeout period\n\t\t\t\t\t- {}",
+                name,
+                self.failed_devices.join("\n\t\t\t\t\t- ")
+            );
+        }
+
+        if !self.blocked_devices.is_empty() {
+            warn!(
+                "Some devices on '{}' were not woken because a device they depend on never came online\n\t\t\t\t\t- {}",
+                name,
+                self.blocked_devices.join("\n\t\t\t\t\t- ")
+            );
+        }
+
+        if !self.skipped_devices.is_empty() {
+            warn!(
+                "Some devices on '{}' were not woken because of their wake policy\n\t\t\t\t\t- {}",
+                name,
+                self.skipped_devices.join("\n\t\t\t\t\t- ")
+            );
+        }
+
+        if !self.unlearned_devices.is_empty() {
+            warn!(
+                "Some devices on '{}' were not woken because their MAC address was never learned\n\t\t\t\t\t- {}",
+                name,
+                self.unlearned_devices.join("\n\t\t\t\t\t- ")
+            );
+        }
+
+        self.restoring = false;
+        self.restoration_started = None;
+        self.waking_started = false;
+        self.devices_shut_down = false;
+        self.dependencies_passed_at.clear();
+    }
+
+    /// `skip_device()` excludes `device` from the restoration, logging `reason` the first time
+    fn skip_device(&mut self, device: &DeviceConfig, reason: &str) {
+        if !self.skipped_devices.contains(&device.friendly_name) {
+            info!(
+                "Skipping restoration for '{}' since {reason}",
+                device.friendly_name
+            );
+
+            self.skipped_devices.push(device.friendly_name.clone());
+        }
+    }
+
+    /// `update()` advances the outage/restoration of the power feeds named `name` using their latest combined status, waking their `devices` once power is restored
+    ///
+    /// Each device is woken according to its wake policy and the WoL settings in `config`, with its own overrides applied.
+    /// Whether a device is online comes from the `snapshot` taken this polling cycle.
+    /// Devices are only woken once every device they depend on is online and settled, and are reported as blocked if a dependency is in `unavailable_devices` or never comes online.
+    pub fn update(
+        &mut self,
+        name: &str,
+        ups_status: &UPSStatus,
+        devices: &[&DeviceConfig],
+        config: &NutjobConfig,
+        snapshot: &ProbeSnapshot,
+        unavailable_devices: &[String],
+    ) {
+        let friendly_names: Vec<String> = devices
+            .iter()
+            .map(|device| device.friendly_name.clone())
+            .collect();
+
+        if ups_status.flags.shutting_down() && !self.devices_shut_down {
+            warn!(target: "UPS", "'{name}' is shutting down its devices ({})", ups_status.flags);
+
+            self.devices_shut_down = true;
+        }
+
+        if ups_status.currently_on_battery && !self.awaiting_ac {
+            // A restoration that didn't finish starts over once power returns
+            let interrupted = self.restoring;
+            if interrupted {
+                warn!(target: "UPS", "'{name}' lost power again before all devices were restored");
+
+                self.restoring = false;
+                self.restoration_started = None;
+                self.waking_started = false;
+                self.dependencies_passed_at.clear();
+            }
+
+            // The previous outage may have ended on the poll this one started, so its outcome is cleared here too
+            self.clear_devices();
+
+            let power_lost_at = self.lost_contact_at.unwrap_or_else(SystemTime::now);
+            let _ = mark_online_devices(devices, &config.liveness, power_lost_at, interrupted);
+
+            if self.lost_contact_at.is_some() {
+                info!(target: "UPS", "'{name}' is assumed to have lost power since its NUT server is unreachable");
+            } else {
+                info!(target: "UPS", "'{name}' switched to battery power");
+            }
+
+            self.awaiting_ac = true;
+        } else if self.restoring || (self.awaiting_ac && !ups_status.currently_on_battery) {
+            self.restoring = true;
+            self.awaiting_ac = false;
+
+            if self.restoration_started.is_none() {
+                self.restoration_started = Some(SystemTime::now());
+                info!(target: "UPS", "'{name}' switched to AC power, restoring devices");
+
+                if devices.iter().any(|device| !device.depends_on.is_empty()) {
+                    let (stages, _) = wake_stages(devices);
+
+                    info!(
+                        "Wake order for '{name}': {}",
+                        stages
+                            .iter()
+                            .enumerate()
+                            .map(|(index, stage)| format!("{}) {}", index + 1, stage.join(", ")))
+                            .collect::<Vec<String>>()
+                            .join(" ")
+                    );
+                }
+            }
+
+            // Whether a dependency is online may date from before it was shut down, so it must also pass a check during the restoration
+            if self.devices_shut_down {
+                for dependency in devices.iter().flat_map(|device| &device.depends_on) {
+                    if snapshot.get(dependency).is_some_and(|probe| probe.passed) {
+                        self.dependencies_passed_at
+                            .entry(dependency.clone())
+                            .or_insert_with(SystemTime::now);
+                    }
+                }
+            }
+
+            let restoration_started = self.restoration_started.unwrap();
+            let restoration_time_elapsed = restoration_started.elapsed().unwrap();
+
+            // Devices that still have to come online, and what the earliest of them is waiting for
+            let mut pending_devices = 0;
+            let mut remaining_delay: Option<u64> = None;
+            let mut required_battery_percentage: Option<u8> = None;
+            let mut throttled: Option<String> = None;
+
+            for device in devices {
+                let device_wol_config = config.wol.with_overrides(&device.wol);
+
+                match device.wake_policy {
+                    WakePolicy::Never => {
+                        self.skip_device(device, "its wake policy is 'never'");
+                        continue;
+                    }
+                    WakePolicy::IfWasOnline if !was_device_online(&device.friendly_name) => {
+                        self.skip_device(
+                            device,
+                            &format!("it was offline before '{name}' switched to battery power"),
+                        );
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if self.restored_devices.contains(&device.friendly_name)
+                    || self.failed_devices.contains(&device.friendly_name)
+                    || self.blocked_devices.contains(&device.friendly_name)
+                    || self.unlearned_devices.contains(&device.friendly_name)
+                {
+                    continue;
+                }
+
+                // Once devices were shut down, only a check passed this cycle shows they came back, rather than their debounced state from before
+                let online = if self.devices_shut_down {
+                    snapshot
+                        .get(&device.friendly_name)
+                        .is_some_and(|probe| probe.passed)
+                } else {
+                    snapshot.is_online(&device.friendly_name)
+                };
+
+                if online {
+                    self.restored_devices.push(device.friendly_name.clone());
+
+                    info!("{} is online!", device.friendly_name);
+                    continue;
+                }
+
+                let restore_timeout = Duration::from_secs(device_wol_config.restore_timeout.into());
+
+                // The restore timeout starts once every dependency has settled
+                let mut ready_at = restoration_started;
+                let mut waiting_on: Option<&String> = None;
+                for dependency in &device.depends_on {
+                    let settle_time = config
+                        .devices
+                        .iter()
+                        .find(|device| device.friendly_name == *dependency)
+                        .map_or(0, |device| device.settle_time);
+
+                    let settled_at =
+                        get_settled_at(dependency, settle_time).and_then(|settled_at| {
+                            // Dependencies powered by other feeds weren't shut down with these devices
+                            if !self.devices_shut_down
+                                || !devices
+                                    .iter()
+                                    .any(|device| device.friendly_name == *dependency)
+                            {
+                                return Some(settled_at);
+                            }
+
+                            let passed_at = self.dependencies_passed_at.get(dependency)?;
+                            return Some(
+                                settled_at
+                                    .max(*passed_at + Duration::from_secs(settle_time.into())),
+                            );
+                        });
+
+                    match settled_at {
+                        Some(settled_at) if settled_at <= SystemTime::now() => {
+                            ready_at = ready_at.max(settled_at);
+                        }
+                        _ => {
+                            waiting_on = Some(dependency);
+                            break;
+                        }
+                    }
+                }
+
+                if let Some(dependency) = waiting_on {
+                    if unavailable_devices.contains(dependency)
+                        || restoration_time_elapsed > restore_timeout
+                    {
+                        self.blocked_devices.push(device.friendly_name.clone());
+
+                        warn!(
+                            "'{}' is blocked since '{dependency}' never came online",
+                            device.friendly_name
+                        );
+                    } else {
+                        pending_devices += 1;
+
+                        debug!(
+                            "Waiting for '{dependency}' to come online before waking '{}'",
+                            device.friendly_name
+                        );
+                    }
+                    continue;
+                }
+
+                // The MAC address of an `arp` device may have been learned or refreshed since the configuration was loaded
+                let mac_address = get_cached_mac_address(&device.friendly_name)
+                    .or_else(|| device.known_mac_address());
+
+                if ready_at.elapsed().unwrap_or_default() > restore_timeout {
+                    if mac_address.is_none() {
+                        self.unlearned_devices.push(device.friendly_name.clone());
+
+                        warn!(
+                            "'{}' could not be woken since its MAC address was never learned",
+                            device.friendly_name
+                        );
+                    } else {
+                        self.failed_devices.push(device.friendly_name.clone());
+
+                        warn!(
+                            "'{}' failed to wake within {} seconds",
+                            device.friendly_name, device_wol_config.restore_timeout
+                        );
+                    }
+                    continue;
+                }
+
+                pending_devices += 1;
+
+                let restore_delay = Duration::from_secs(device_wol_config.restore_delay.into());
+                if restoration_time_elapsed < restore_delay {
+                    let remaining = (restore_delay - restoration_time_elapsed).as_secs();
+                    remaining_delay = Some(remaining_delay.map_or(remaining, |r| r.min(remaining)));
+                    continue;
+                }
+
+                // Devices aren't held back forever by a UPS that doesn't report its battery charge
+                if let Some(battery_percentage) = ups_status.battery_percentage
+                    && battery_percentage < f64::from(device_wol_config.min_battery_percentage)
+                {
+                    let required = device_wol_config.min_battery_percentage;
+                    required_battery_percentage =
+                        Some(required_battery_percentage.map_or(required, |r| r.min(required)));
+                    continue;
+                }
+
+                if !self.waking_started {
+                    if ups_status.battery_percentage.is_some() {
+                        info!(
+                            "Waking devices on '{name}'; restore delay has elapsed & battery is above the minimum percentage"
+                        );
+                    } else {
+                        warn!(
+                            "Waking devices on '{name}'; restore delay has elapsed, but the battery charge is unknown so the minimum percentage can't be checked"
+                        );
+                    }
+                    self.waking_started = true;
+                }
+
+                let Some(mac_address) = mac_address else {
+                    debug!(target: "WoL", "Unable to wake '{}' until its MAC address is learned", device.friendly_name);
+                    continue;
+                };
+
+                if can_attempt_wake(&device.friendly_name, device_wol_config.reattempt_delay) {
+                    if let Some(reason) = get_wake_throttle(
+                        &device_wol_config,
+                        ups_status,
+                        &device.friendly_name,
+                        unavailable_devices,
+                    ) {
+                        throttled = Some(reason);
+                        continue;
+                    }
+
+                    if wakeonlan(mac_address, &device.friendly_name).is_ok() {
+                        let _ = mark_wol_attempted(&device.friendly_name);
+                    }
+                } else {
+                    debug!(target: "WoL", "Waiting for {} seconds to elapse before attempting to wake {} again", device_wol_config.reattempt_delay, device.friendly_name);
+                }
+            }
+
+            if let Some(remaining_delay) = remaining_delay {
+                warn!("Waiting {remaining_delay} more second(s) before waking devices on '{name}'");
+            }
+
+            if let Some(required_battery_percentage) = required_battery_percentage {
+                warn!(
+                    "Waiting for battery of '{}' to reach minimum percentage before waking devices ({}/{}%)",
+                    name,
+                    format_value(ups_status.battery_percentage, "%"),
+                    required_battery_percentage
+                );
+            }
+
+            if let Some(reason) = throttled {
+                warn!("Staggering wakes on '{name}': {reason}");
+            }
+
+            if pending_devices == 0 {
+                self.finish_restoration(name);
+            }
+        } else if !ups_status.currently_on_battery && !self.restoring {
+            self.clear_devices();
+
+            let _ = reset_device_states(&friendly_names);
+            debug!("Reset device states for '{name}'");
+        }
+    }
+}
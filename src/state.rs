@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::{Error, ErrorKind, Read, Result, Write},
     path::Path,
@@ -7,13 +8,18 @@ use std::{
 };
 
 use bincode::{Decode, Encode, config};
-use log::debug;
+use log::{debug, info, warn};
 
-use crate::{config::DeviceConfig, monitoring::UPSStatus};
+use crate::{
+    config::{DeviceConfig, LivenessConfig, UpsConfig},
+    mac::MacAddress,
+    monitoring::{UPSStatus, UpsFlag, UpsFlags},
+};
 
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct NutjobState {
-    pub ups: UPSStatus,
+    /// Last known status of each UPS, keyed by its `name`
+    pub ups: BTreeMap<String, UPSStatus>,
     pub devices: Vec<DeviceState>,
 }
 
@@ -22,17 +28,101 @@ pub struct DeviceState {
     pub friendly_name: String,
     pub online_before_shutdown: bool,
     pub online: bool,
+    /// The address the device was last pinged at, `None` if its current IP address couldn't be found
+    pub address: Option<String>,
+    /// When the device was last seen coming online, `None` while it is offline
+    pub online_since: Option<SystemTime>,
+    /// Number of checks in a row the device has passed, reset when one fails
+    pub consecutive_successes: u8,
+    /// Number of checks in a row the device has failed, reset when one passes
+    pub consecutive_failures: u8,
+    /// When the device last passed a check, even if that wasn't enough to consider it online
+    pub last_seen_online: Option<SystemTime>,
     pub wol_sent_at: Option<SystemTime>,
+    /// The last MAC address resolved for a device with `mac_address: arp`, used when it can't be resolved live
+    pub mac_address: Option<MacAddress>,
+    /// When `mac_address` was last resolved from the device
+    pub mac_resolved_at: Option<SystemTime>,
+}
+
+/// Written at the start of the state file, followed by [`STATE_VERSION`], to tell it apart from the unversioned format of earlier releases
+const STATE_MAGIC: [u8; 4] = *b"NJST";
+
+/// Version of the state file format, increased whenever [`NutjobState`] changes
+const STATE_VERSION: u16 = 1;
+
+/// Key the status of the single UPS of an unversioned state file is kept under until [`init_state`] knows which UPS it belongs to
+const LEGACY_UPS_NAME: &str = "";
+
+/// The status of the UPS in the unversioned state file of earlier releases
+#[derive(Decode)]
+struct LegacyUPSStatus {
+    currently_on_battery: bool,
+    battery_percentage: u8,
+    load_percentage: u8,
+}
+
+/// A device in the unversioned state file of earlier releases
+#[derive(Decode)]
+struct LegacyDeviceState {
+    friendly_name: String,
+    online_before_shutdown: bool,
+    online: bool,
+    wol_sent_at: Option<SystemTime>,
+}
+
+/// The unversioned state file of earlier releases, which only tracked a single UPS
+#[derive(Decode)]
+struct LegacyState {
+    ups: LegacyUPSStatus,
+    devices: Vec<LegacyDeviceState>,
+}
+
+impl From<LegacyState> for NutjobState {
+    fn from(legacy: LegacyState) -> Self {
+        let mut flags = UpsFlags::default();
+        flags.insert(if legacy.ups.currently_on_battery {
+            UpsFlag::OnBattery
+        } else {
+            UpsFlag::Online
+        });
+
+        return NutjobState {
+            ups: BTreeMap::from([(
+                LEGACY_UPS_NAME.to_string(),
+                UPSStatus {
+                    currently_on_battery: legacy.ups.currently_on_battery,
+                    flags,
+                    battery_percentage: Some(legacy.ups.battery_percentage.into()),
+                    load_percentage: Some(legacy.ups.load_percentage.into()),
+                    ..Default::default()
+                },
+            )]),
+            devices: legacy
+                .devices
+                .into_iter()
+                .map(|device| DeviceState {
+                    friendly_name: device.friendly_name,
+                    online_before_shutdown: device.online_before_shutdown,
+                    online: device.online,
+                    address: None,
+                    online_since: None,
+                    consecutive_successes: 0,
+                    consecutive_failures: 0,
+                    last_seen_online: None,
+                    wol_sent_at: device.wol_sent_at,
+                    mac_address: None,
+                    mac_resolved_at: None,
+                })
+                .collect(),
+        };
+    }
 }
 
-static STATE_PATH: &'static str = "/nutjob/state";
+static STATE_PATH: Mutex<String> = Mutex::new(String::new());
 
 static STATE: Mutex<NutjobState> = Mutex::new(NutjobState {
-    ups: UPSStatus {
-        currently_on_battery: false,
-        battery_percentage: 100,
-        load_percentage: 0,
-    },
+    ups: BTreeMap::new(),
     devices: Vec::new(),
 });
 
@@ -43,45 +133,191 @@ pub fn read_vector(path: &Path) -> Result<Vec<u8>> {
     return Ok(data);
 }
 
-pub fn read_state_from_file() -> NutjobState {
+/// `set_state_path()` sets the path of the file that the state is persisted to
+pub fn set_state_path(path: &str) {
+    let mut guard = STATE_PATH.lock().unwrap();
+
+    *guard = path.to_string();
+}
+
+fn get_state_path() -> String {
+    let guard = STATE_PATH.lock().unwrap();
+
+    return guard.clone();
+}
+
+/// `decode_state()` decodes the contents of a state file, migrating the unversioned format of earlier releases
+fn decode_state(data: &[u8]) -> std::result::Result<NutjobState, String> {
     let config = config::standard();
 
-    let vector = read_vector(Path::new(STATE_PATH)).unwrap_or_default();
+    let Some(versioned) = data.strip_prefix(&STATE_MAGIC) else {
+        // The whole file has to be decoded, so a file that merely starts like an old one isn't mistaken for it
+        return match bincode::decode_from_slice::<LegacyState, _>(data, config) {
+            Ok((legacy, length)) if length == data.len() => {
+                info!("Migrating the state file from an earlier release");
+                Ok(NutjobState::from(legacy))
+            }
+            _ => Err(String::from("it isn't in a known format")),
+        };
+    };
 
-    let (decoded, _len): (NutjobState, usize) = bincode::decode_from_slice(&vector, config)
-        .unwrap_or((
-            NutjobState {
-                ups: UPSStatus {
-                    currently_on_battery: false,
-                    battery_percentage: 100,
-                    load_percentage: 0,
-                },
-                devices: Vec::new(),
-            },
-            0,
+    let (version, header_length): (u16, usize) =
+        bincode::decode_from_slice(versioned, config).map_err(|e| e.to_string())?;
+
+    if version != STATE_VERSION {
+        return Err(format!(
+            "it is version {version}, but only version {STATE_VERSION} is supported"
         ));
+    }
+
+    let (state, _length): (NutjobState, usize) =
+        bincode::decode_from_slice(&versioned[header_length..], config)
+            .map_err(|e| e.to_string())?;
 
-    return decoded;
+    return Ok(state);
 }
 
-pub fn init_state(device_configs: &Vec<DeviceConfig>) -> Result<()> {
-    let mut state = read_state_from_file();
+/// `read_state_from_file()` reads the persisted state, starting over with an empty state (and a warning, unless there is no state file yet) if it can't be read
+pub fn read_state_from_file() -> NutjobState {
+    let path = get_state_path();
+
+    let empty = NutjobState {
+        ups: BTreeMap::new(),
+        devices: Vec::new(),
+    };
+
+    let data = match read_vector(Path::new(&path)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return empty,
+        Err(e) => {
+            warn!("Unable to read the state file '{path}', starting with an empty state: {e}");
+            return empty;
+        }
+    };
+
+    return match decode_state(&data) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!(
+                "Unable to decode the state file '{path}' since {e}, so UPS statuses and devices online before an outage are lost"
+            );
+            empty
+        }
+    };
+}
 
-    state.devices = device_configs
-        .into_iter()
-        .map(|device| DeviceState {
-            friendly_name: device.friendly_name.clone(),
-            online_before_shutdown: false,
-            online: false,
-            wol_sent_at: None,
+/// `reconcile_devices()` matches `device_configs` against the tracked device states by `friendly_name`
+///
+/// Devices that are still configured keep their state, new devices start with a fresh state and removed devices are dropped.
+/// The MAC addresses resolved while loading the configuration are cached for devices with `mac_address: arp`.
+fn reconcile_devices(
+    devices: Vec<DeviceState>,
+    device_configs: &[DeviceConfig],
+) -> Vec<DeviceState> {
+    return device_configs
+        .iter()
+        .map(|device_config| {
+            let mut device = match devices
+                .iter()
+                .find(|device| device.friendly_name == device_config.friendly_name)
+            {
+                Some(device) => device.clone(),
+                None => DeviceState {
+                    friendly_name: device_config.friendly_name.clone(),
+                    online_before_shutdown: false,
+                    online: false,
+                    address: None,
+                    online_since: None,
+                    consecutive_successes: 0,
+                    consecutive_failures: 0,
+                    last_seen_online: None,
+                    wol_sent_at: None,
+                    mac_address: None,
+                    mac_resolved_at: None,
+                },
+            };
+
+            if device_config.resolves_mac_address() {
+                if let Some(mac_address) = device_config.known_mac_address() {
+                    cache_mac_address(&mut device, mac_address);
+                }
+            } else {
+                device.mac_address = None;
+                device.mac_resolved_at = None;
+            }
+
+            return device;
         })
         .collect();
+}
+
+/// `cache_mac_address()` caches `mac_address` as the resolved MAC address of `device`, logging when it changed
+fn cache_mac_address(device: &mut DeviceState, mac_address: MacAddress) {
+    match device.mac_address {
+        Some(cached) if cached == mac_address => return,
+        Some(cached) => info!(
+            "MAC address of '{}' changed from {cached} to {mac_address}",
+            device.friendly_name
+        ),
+        None => info!(
+            "Learned MAC address {mac_address} for '{}'",
+            device.friendly_name
+        ),
+    }
+
+    device.mac_address = Some(mac_address);
+    device.mac_resolved_at = Some(SystemTime::now());
+}
+
+pub fn init_state(ups_configs: &[UpsConfig], device_configs: &[DeviceConfig]) -> Result<()> {
+    let mut state = read_state_from_file();
+
+    // An unversioned state file only tracked a single UPS
+    if let Some(ups_status) = state.ups.remove(LEGACY_UPS_NAME) {
+        if let [ups] = ups_configs {
+            state.ups.insert(ups.name.clone(), ups_status);
+        } else {
+            warn!(
+                "Dropping the UPS status of the migrated state file, since it can't tell which of the configured UPSes it belongs to"
+            );
+        }
+    }
+
+    state
+        .ups
+        .retain(|name, _| ups_configs.iter().any(|ups| ups.name == *name));
+    state.devices = reconcile_devices(state.devices, device_configs);
+
+    // Whether devices are online is only known once they are checked again, as they may have gone offline (such as losing power) while the service was stopped
+    for device in &mut state.devices {
+        device.online = false;
+        device.online_since = None;
+        device.consecutive_successes = 0;
+        device.consecutive_failures = 0;
+    }
 
     update_state(state)?;
 
     return save_state();
 }
 
+/// `update_tracked_devices()` updates the tracked UPSes and devices to match a reloaded configuration, keeping the state of unchanged devices
+pub fn update_tracked_devices(
+    ups_configs: &[UpsConfig],
+    device_configs: &[DeviceConfig],
+) -> Result<()> {
+    let mut state = get_state();
+
+    state
+        .ups
+        .retain(|name, _| ups_configs.iter().any(|ups| ups.name == *name));
+
+    return update_state(NutjobState {
+        ups: state.ups,
+        devices: reconcile_devices(state.devices, device_configs),
+    });
+}
+
 pub fn get_state() -> NutjobState {
     let guard = STATE.lock().unwrap();
 
@@ -90,7 +326,7 @@ pub fn get_state() -> NutjobState {
 
 fn save_vector(path: &Path, encoded: &[u8]) -> Result<()> {
     let mut file = File::create(path)?;
-    file.write_all(&encoded[..])?;
+    file.write_all(encoded)?;
     return Ok(());
 }
 
@@ -98,9 +334,11 @@ pub fn save_state() -> Result<()> {
     let config = config::standard();
 
     let guard = STATE.lock().unwrap();
-    let encoded = bincode::encode_to_vec(guard.clone(), config).unwrap();
+    let mut encoded = STATE_MAGIC.to_vec();
+    encoded.extend(bincode::encode_to_vec(STATE_VERSION, config).unwrap());
+    encoded.extend(bincode::encode_to_vec(guard.clone(), config).unwrap());
 
-    return save_vector(Path::new(STATE_PATH), &encoded);
+    return save_vector(Path::new(&get_state_path()), &encoded);
 }
 
 pub fn update_state(new_state: NutjobState) -> Result<()> {
@@ -131,7 +369,15 @@ pub fn update_device_state(new_device_state: DeviceState) -> Result<()> {
     });
 }
 
-pub fn mark_device_online(friendly_name: String, online: bool) -> Result<()> {
+/// `mark_device_online()` records whether the device named `friendly_name` passed its check at `address`
+///
+/// The device only switches between online and offline after the number of consecutive checks in `liveness`, and whether it is now considered online is returned.
+pub fn mark_device_online(
+    friendly_name: String,
+    passed: bool,
+    address: Option<String>,
+    liveness: &LivenessConfig,
+) -> Result<bool> {
     let state = get_state();
 
     let _device = state
@@ -141,15 +387,48 @@ pub fn mark_device_online(friendly_name: String, online: bool) -> Result<()> {
 
     match _device {
         Some(mut device) => {
-            device.online = online;
+            if passed {
+                device.consecutive_successes = device.consecutive_successes.saturating_add(1);
+                device.consecutive_failures = 0;
+                device.last_seen_online = Some(SystemTime::now());
+
+                if !device.online && device.consecutive_successes >= liveness.successes_to_online {
+                    debug!("'{friendly_name}' is online");
+                    device.online = true;
+                    device.online_since = Some(SystemTime::now());
+                }
+            } else {
+                device.consecutive_failures = device.consecutive_failures.saturating_add(1);
+                device.consecutive_successes = 0;
+
+                if device.online && device.consecutive_failures >= liveness.failures_to_offline {
+                    debug!("'{friendly_name}' is offline");
+                    device.online = false;
+                    device.online_since = None;
+                }
+            }
+            device.address = address;
 
-            return update_device_state(device);
+            let online = device.online;
+            update_device_state(device)?;
+
+            return Ok(online);
         }
         None => Err(Error::new(ErrorKind::InvalidInput, "Device not found")),
     }
 }
 
-pub fn mark_online_devices() -> Result<()> {
+/// `mark_online_devices()` records whether each of the `devices` was online when it lost power at `power_lost_at`
+///
+/// Devices that passed a check since their `online_lookback` window before `power_lost_at` count as online, even if they are currently considered offline.
+/// If the outage `interrupted` a restoration, devices that were online before the previous outage still count as online, since they may not have been woken yet.
+/// WoL packets sent before the outage are forgotten.
+pub fn mark_online_devices(
+    devices: &[&DeviceConfig],
+    liveness: &LivenessConfig,
+    power_lost_at: SystemTime,
+    interrupted: bool,
+) -> Result<()> {
     let state = get_state();
 
     return update_state(NutjobState {
@@ -159,30 +438,59 @@ pub fn mark_online_devices() -> Result<()> {
             .clone()
             .into_iter()
             .map(|device| {
+                let Some(device_config) = devices
+                    .iter()
+                    .find(|device_config| device_config.friendly_name == device.friendly_name)
+                else {
+                    return device;
+                };
+
+                let online_lookback = Duration::from_secs(
+                    liveness
+                        .with_overrides(&device_config.liveness)
+                        .online_lookback
+                        .into(),
+                );
+                // Devices seen after power was lost (such as while the NUT server was unreachable) were online too
+                let seen_recently = device.last_seen_online.is_some_and(|last_seen_online| {
+                    power_lost_at
+                        .duration_since(last_seen_online)
+                        .map_or(true, |elapsed| elapsed <= online_lookback)
+                });
+                let online_before_shutdown = device.online
+                    || seen_recently
+                    || (interrupted && device.online_before_shutdown);
+
                 debug!(
                     target: "nutjob",
                     "'{}' was{} online before shutdown",
                     device.friendly_name,
-                    if !device.online { " not" } else { "" }
+                    if !online_before_shutdown { " not" } else { "" }
                 );
                 return DeviceState {
                     friendly_name: device.friendly_name,
-                    online_before_shutdown: device.online,
+                    online_before_shutdown,
                     online: device.online,
-                    wol_sent_at: device.wol_sent_at,
+                    address: device.address,
+                    online_since: device.online_since,
+                    consecutive_successes: device.consecutive_successes,
+                    consecutive_failures: device.consecutive_failures,
+                    last_seen_online: device.last_seen_online,
+                    wol_sent_at: None,
+                    mac_address: device.mac_address,
+                    mac_resolved_at: device.mac_resolved_at,
                 };
             })
             .collect(),
     });
 }
 
-pub fn update_ups_state(new_ups_state: UPSStatus) -> Result<()> {
-    let state = get_state();
+pub fn update_ups_state(name: &str, new_ups_state: UPSStatus) -> Result<()> {
+    let mut state = get_state();
 
-    return update_state(NutjobState {
-        ups: new_ups_state.clone(),
-        devices: state.devices.clone(),
-    });
+    state.ups.insert(name.to_string(), new_ups_state);
+
+    return update_state(state);
 }
 
 pub fn was_device_online(friendly_name: &str) -> bool {
@@ -199,6 +507,22 @@ pub fn was_device_online(friendly_name: &str) -> bool {
     }
 }
 
+/// `get_settled_at()` returns when the device named `friendly_name` has been online for `settle_time` seconds, or `None` if it is offline
+///
+/// The returned time may be in the future if the device came online recently
+pub fn get_settled_at(friendly_name: &str, settle_time: u16) -> Option<SystemTime> {
+    let state = get_state();
+
+    let device_state = state
+        .devices
+        .iter()
+        .find(|device| device.friendly_name == friendly_name)?;
+
+    return device_state
+        .online_since
+        .map(|online_since| online_since + Duration::from_secs(settle_time.into()));
+}
+
 pub fn can_attempt_wake(friendly_name: &str, reattempt_delay: u16) -> bool {
     let state = get_state();
 
@@ -219,6 +543,86 @@ pub fn can_attempt_wake(friendly_name: &str, reattempt_delay: u16) -> bool {
     }
 }
 
+/// `count_waking_devices()` returns how many devices were sent a WoL packet but haven't come online yet, ignoring the devices in `excluded`
+pub fn count_waking_devices(excluded: &[String]) -> usize {
+    let state = get_state();
+
+    return state
+        .devices
+        .iter()
+        .filter(|device| {
+            device.wol_sent_at.is_some()
+                && !device.online
+                && !excluded.contains(&device.friendly_name)
+        })
+        .count();
+}
+
+/// `get_last_wol_sent_at()` returns when the most recent WoL packet was sent to any device
+pub fn get_last_wol_sent_at() -> Option<SystemTime> {
+    let state = get_state();
+
+    return state
+        .devices
+        .iter()
+        .filter_map(|device| device.wol_sent_at)
+        .max();
+}
+
+/// `get_cached_mac_address()` returns the last MAC address resolved for the device named `friendly_name`
+///
+/// The state file is checked when the device isn't tracked yet, such as while the configuration is first loaded
+pub fn get_cached_mac_address(friendly_name: &str) -> Option<MacAddress> {
+    let find_cached = |state: NutjobState| {
+        return state
+            .devices
+            .into_iter()
+            .find(|device| device.friendly_name == friendly_name)
+            .and_then(|device| device.mac_address);
+    };
+
+    return find_cached(get_state()).or_else(|| find_cached(read_state_from_file()));
+}
+
+/// `update_mac_address()` records `mac_address` as freshly resolved for the device named `friendly_name`, logging when it changed
+pub fn update_mac_address(friendly_name: &str, mac_address: MacAddress) -> Result<()> {
+    let mut state = get_state();
+
+    match state
+        .devices
+        .iter_mut()
+        .find(|device| device.friendly_name == friendly_name)
+    {
+        Some(device) => {
+            cache_mac_address(device, mac_address);
+            device.mac_resolved_at = Some(SystemTime::now());
+        }
+        None => return Err(Error::new(ErrorKind::InvalidInput, "Device not found")),
+    }
+
+    return update_state(state);
+}
+
+/// `needs_mac_refresh()` returns true if the device named `friendly_name` has no cached MAC address yet, or it was resolved more than `refresh_interval` seconds ago
+///
+/// A `refresh_interval` of 0 never refreshes a MAC address once it has been resolved
+pub fn needs_mac_refresh(friendly_name: &str, refresh_interval: u32) -> bool {
+    let state = get_state();
+
+    return state
+        .devices
+        .iter()
+        .find(|device| device.friendly_name == friendly_name)
+        .is_some_and(|device| match device.mac_resolved_at {
+            Some(_) if device.mac_address.is_some() && refresh_interval == 0 => false,
+            Some(mac_resolved_at) if device.mac_address.is_some() => {
+                mac_resolved_at.elapsed().unwrap_or_default()
+                    >= Duration::from_secs(refresh_interval.into())
+            }
+            _ => true,
+        });
+}
+
 pub fn mark_wol_attempted(friendly_name: &str) -> Result<()> {
     let state = get_state();
 
@@ -232,17 +636,25 @@ pub fn mark_wol_attempted(friendly_name: &str) -> Result<()> {
                 friendly_name: device.friendly_name.clone(),
                 online_before_shutdown: device.online_before_shutdown,
                 online: device.online,
+                address: device.address,
+                online_since: device.online_since,
+                consecutive_successes: device.consecutive_successes,
+                consecutive_failures: device.consecutive_failures,
+                last_seen_online: device.last_seen_online,
                 wol_sent_at: if device.friendly_name == friendly_name {
                     Some(SystemTime::now())
                 } else {
                     device.wol_sent_at
                 },
+                mac_address: device.mac_address,
+                mac_resolved_at: device.mac_resolved_at,
             })
             .collect(),
     });
 }
 
-pub fn reset_device_states() -> Result<()> {
+/// `reset_device_states()` clears the outage bookkeeping of the devices named in `friendly_names`
+pub fn reset_device_states(friendly_names: &[String]) -> Result<()> {
     let state = get_state();
 
     return update_state(NutjobState {
@@ -252,11 +664,22 @@ pub fn reset_device_states() -> Result<()> {
             .clone()
             .into_iter()
             .map(|device| {
+                if !friendly_names.contains(&device.friendly_name) {
+                    return device;
+                }
+
                 return DeviceState {
                     friendly_name: device.friendly_name,
                     online_before_shutdown: false,
                     online: device.online,
+                    address: device.address,
+                    online_since: device.online_since,
+                    consecutive_successes: device.consecutive_successes,
+                    consecutive_failures: device.consecutive_failures,
+                    last_seen_online: device.last_seen_online,
                     wol_sent_at: None,
+                    mac_address: device.mac_address,
+                    mac_resolved_at: device.mac_resolved_at,
                 };
             })
             .collect(),
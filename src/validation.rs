@@ -0,0 +1,666 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::{
+    DeviceConfig, HealthCheckConfig, LivenessConfig, MacAddressSource, NutjobConfig, PingConfig,
+    UpsConfig, WakeOnLanConfig, string_to_level_filter,
+};
+use crate::dependencies::{find_cycle, wake_stages};
+use crate::leases::read_mac_source;
+use crate::mac::{MacAddress, find_device_address, resolve_device_mac_address};
+use crate::monitoring::is_device_online;
+use crate::state::get_cached_mac_address;
+
+/// How serious a [`ConfigProblem`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The configuration can't be used
+    Error,
+    /// The configuration can be used, but probably not as intended
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A single problem found in the configuration file
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    /// Path of the field the problem was found in, such as `devices[1].mac_address`
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    pub fn error(field: &str, message: String) -> Self {
+        return ConfigProblem {
+            severity: Severity::Error,
+            field: field.to_string(),
+            message,
+        };
+    }
+
+    pub fn warning(field: &str, message: String) -> Self {
+        return ConfigProblem {
+            severity: Severity::Warning,
+            field: field.to_string(),
+            message,
+        };
+    }
+}
+
+/// Every problem found in a configuration file that can't be used, including any warnings
+#[derive(Debug)]
+pub struct ConfigErrors {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_problems(&self.problems))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// `separate_key_segments()` adds the missing `.` after list indexes in keys reported by the `config` crate (e.g. `devices[0]mac_address` -> `devices[0].mac_address`)
+fn separate_key_segments(key: &str) -> String {
+    let mut separated = String::new();
+    let mut characters = key.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        separated.push(character);
+
+        if character == ']'
+            && characters
+                .peek()
+                .is_some_and(|next| *next != '[' && *next != '.')
+        {
+            separated.push('.');
+        }
+    }
+
+    return separated;
+}
+
+impl From<config::ConfigError> for ConfigErrors {
+    fn from(error: config::ConfigError) -> Self {
+        let (field, message) = match &error {
+            config::ConfigError::NotFound(key) => (Some(key.clone()), error.to_string()),
+            config::ConfigError::Type { key, .. } => (key.clone(), error.to_string()),
+            // The key is already in the field column, so only report the underlying error
+            config::ConfigError::At { key, error, .. } => (key.clone(), error.to_string()),
+            _ => (None, error.to_string()),
+        };
+
+        let field = field.map(|field| separate_key_segments(&field));
+
+        return ConfigErrors {
+            problems: vec![ConfigProblem::error(
+                &field.unwrap_or(String::from("-")),
+                message,
+            )],
+        };
+    }
+}
+
+/// `has_errors()` returns true if any of the `problems` is an [`Severity::Error`]
+pub fn has_errors(problems: &[ConfigProblem]) -> bool {
+    return problems
+        .iter()
+        .any(|problem| problem.severity == Severity::Error);
+}
+
+/// `format_problems()` formats `problems` as a table with a severity, field and problem column
+pub fn format_problems(problems: &[ConfigProblem]) -> String {
+    let severity_width = problems
+        .iter()
+        .map(|problem| problem.severity.to_string().len())
+        .chain(["SEVERITY".len()])
+        .max()
+        .unwrap_or_default();
+    let field_width = problems
+        .iter()
+        .map(|problem| problem.field.len())
+        .chain(["FIELD".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:severity_width$}  {:field_width$}  PROBLEM",
+        "SEVERITY", "FIELD"
+    );
+
+    for problem in problems {
+        table.push_str(&format!(
+            "\n{:severity_width$}  {:field_width$}  {}",
+            problem.severity.to_string(),
+            problem.field,
+            problem.message
+        ));
+    }
+
+    return table;
+}
+
+/// `validate_ups()` checks the UPS definitions of `config`, moving the deprecated single UPS in `nut` into `config.ups`
+fn validate_ups(config: &mut NutjobConfig) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    match (config.nut.ups_name.take(), config.nut.host.take()) {
+        (Some(ups_name), Some(host)) => {
+            if config.ups.is_empty() {
+                config.ups.push(UpsConfig {
+                    name: ups_name.clone(),
+                    ups_name,
+                    host,
+                    username: config.nut.username.take(),
+                    password: config.nut.password.take(),
+                });
+            } else {
+                problems.push(ConfigProblem::error(
+                    "nut.ups_name",
+                    String::from("A UPS in 'nut' can't be combined with the 'ups' list: Move it into the 'ups' list"),
+                ));
+            }
+        }
+        (None, None) => {}
+        (Some(_), None) => problems.push(ConfigProblem::error(
+            "nut.host",
+            String::from("Missing host for the UPS given in 'nut'"),
+        )),
+        (None, Some(_)) => problems.push(ConfigProblem::error(
+            "nut.ups_name",
+            String::from("Missing UPS name for the host given in 'nut'"),
+        )),
+    }
+
+    if config.ups.is_empty() {
+        problems.push(ConfigProblem::error(
+            "ups",
+            String::from("At least one UPS must be configured"),
+        ));
+    }
+
+    let mut names: HashMap<String, usize> = HashMap::new();
+
+    for (index, ups) in config.ups.iter().enumerate() {
+        if ups.name.is_empty() {
+            problems.push(ConfigProblem::error(
+                &format!("ups[{index}].name"),
+                String::from("Name must not be empty"),
+            ));
+        } else if let Some(first_index) = names.get(&ups.name) {
+            problems.push(ConfigProblem::error(
+                &format!("ups[{index}].name"),
+                format!(
+                    "Duplicate UPS name '{}' (first used by ups[{first_index}])",
+                    ups.name
+                ),
+            ));
+        } else {
+            names.insert(ups.name.clone(), index);
+        }
+
+        if ups.password.is_some() && ups.username.is_none() {
+            problems.push(ConfigProblem::warning(
+                &format!("ups[{index}].password"),
+                String::from(
+                    "A password was given without a username: The password will not be sent",
+                ),
+            ));
+        }
+    }
+
+    return problems;
+}
+
+/// `validate_wol()` checks the WoL settings in `wol`, reporting problems under the `prefix` field
+fn validate_wol(wol: &WakeOnLanConfig, prefix: &str) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    if wol.min_battery_percentage > 100 {
+        problems.push(ConfigProblem::error(
+            &format!("{prefix}.min_battery_percentage"),
+            format!(
+                "Percentage must be between 0 and 100 (got {})",
+                wol.min_battery_percentage
+            ),
+        ));
+    }
+
+    if let Some(max_load_percentage) = wol.max_load_percentage
+        && max_load_percentage > 100
+    {
+        problems.push(ConfigProblem::error(
+            &format!("{prefix}.max_load_percentage"),
+            format!("Percentage must be between 0 and 100 (got {max_load_percentage})"),
+        ));
+    }
+
+    if wol.restore_timeout < wol.restore_delay {
+        problems.push(ConfigProblem::error(
+            &format!("{prefix}.restore_timeout"),
+            format!(
+                "Restore timeout ({}s) must not be less than the restore delay ({}s)",
+                wol.restore_timeout, wol.restore_delay
+            ),
+        ));
+    }
+
+    return problems;
+}
+
+/// `validate_ping()` checks the ping settings in `ping`
+fn validate_ping(ping: &PingConfig) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    if ping.timeout == 0 {
+        problems.push(ConfigProblem::error(
+            "ping.timeout",
+            String::from("Timeout must be greater than 0"),
+        ));
+    }
+
+    if ping.count == 0 {
+        problems.push(ConfigProblem::error(
+            "ping.count",
+            String::from("Count must be greater than 0"),
+        ));
+    }
+
+    if ping.required_successes == 0 || ping.required_successes > ping.count {
+        problems.push(ConfigProblem::error(
+            "ping.required_successes",
+            format!(
+                "Required successes must be between 1 and the count ({}) (got {})",
+                ping.count, ping.required_successes
+            ),
+        ));
+    }
+
+    return problems;
+}
+
+/// `validate_liveness()` checks the liveness thresholds in `liveness`, reporting problems under the `prefix` field
+fn validate_liveness(liveness: &LivenessConfig, prefix: &str) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    if liveness.successes_to_online == 0 {
+        problems.push(ConfigProblem::error(
+            &format!("{prefix}.successes_to_online"),
+            String::from("At least 1 successful check is needed to come online"),
+        ));
+    }
+
+    if liveness.failures_to_offline == 0 {
+        problems.push(ConfigProblem::error(
+            &format!("{prefix}.failures_to_offline"),
+            String::from("At least 1 failed check is needed to go offline"),
+        ));
+    }
+
+    return problems;
+}
+
+/// `validate_check()` checks the health check `check` of a device, reporting problems under the `prefix` field
+fn validate_check(check: &HealthCheckConfig, prefix: &str) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    let timeout = match check {
+        HealthCheckConfig::Icmp | HealthCheckConfig::Arp => None,
+        HealthCheckConfig::Tcp { timeout, .. }
+        | HealthCheckConfig::Http { timeout, .. }
+        | HealthCheckConfig::Ssh { timeout, .. }
+        | HealthCheckConfig::Command { timeout, .. } => *timeout,
+    };
+
+    if timeout == Some(0) {
+        problems.push(ConfigProblem::error(
+            &format!("{prefix}.timeout"),
+            String::from("Timeout must be greater than 0"),
+        ));
+    }
+
+    match check {
+        HealthCheckConfig::Tcp { port: 0, .. } | HealthCheckConfig::Ssh { port: 0, .. } => {
+            problems.push(ConfigProblem::error(
+                &format!("{prefix}.port"),
+                String::from("Port must be greater than 0"),
+            ));
+        }
+        HealthCheckConfig::Http {
+            url,
+            expected_status,
+            ..
+        } => {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(ConfigProblem::error(
+                    &format!("{prefix}.url"),
+                    format!("'{url}' must start with http:// or https://"),
+                ));
+            }
+
+            if !(100..=599).contains(expected_status) {
+                problems.push(ConfigProblem::error(
+                    &format!("{prefix}.expected_status"),
+                    format!("Status code must be between 100 and 599 (got {expected_status})"),
+                ));
+            }
+        }
+        HealthCheckConfig::Command { command, .. } if command.trim().is_empty() => {
+            problems.push(ConfigProblem::error(
+                &format!("{prefix}.command"),
+                String::from("Command must not be empty"),
+            ));
+        }
+        _ => {}
+    }
+
+    return problems;
+}
+
+/// `validate_config()` checks every field of `config`, resolving `arp` MAC addresses along the way.
+///
+/// Devices with problems are dropped from `config` and reported as warnings, unless `config.strict` is set, in which case they are reported as errors.
+/// Hosts are only pinged when `check_reachability` is set, since devices are expected to be offline while restoring from an outage.
+///
+/// It returns every problem found.
+pub fn validate_config(config: &mut NutjobConfig, check_reachability: bool) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+
+    if let Err(e) = string_to_level_filter(&config.log_level) {
+        problems.push(ConfigProblem::error(
+            "log_level",
+            format!(
+                "{e} ('{}'): Expected one of TRACE, DEBUG, INFO, WARN, ERROR, OFF",
+                config.log_level
+            ),
+        ));
+    }
+
+    if config.nut.polling_interval == 0 {
+        problems.push(ConfigProblem::error(
+            "nut.polling_interval",
+            String::from("Polling interval must be greater than 0"),
+        ));
+    }
+
+    problems.append(&mut validate_ups(config));
+
+    problems.append(&mut validate_wol(&config.wol, "wol"));
+
+    problems.append(&mut validate_ping(&config.ping));
+
+    problems.append(&mut validate_liveness(&config.liveness, "liveness"));
+
+    for (index, source) in config.mac_sources.iter().enumerate() {
+        if let Err(e) = read_mac_source(source) {
+            problems.push(ConfigProblem::warning(
+                &format!("mac_sources[{index}].path"),
+                format!("Unable to read '{}': {e}", source.path),
+            ));
+        }
+    }
+
+    let strict = config.strict;
+    let global_wol = config.wol.clone();
+    let mac_sources = config.mac_sources.clone();
+    let ping_config = config.ping;
+    let global_liveness = config.liveness;
+    let ups_names: Vec<String> = config.ups.iter().map(|ups| ups.name.clone()).collect();
+    let mut friendly_names: HashMap<String, usize> = HashMap::new();
+    let mut mac_addresses: HashMap<MacAddress, usize> = HashMap::new();
+    let all_friendly_names: Vec<String> = config
+        .devices
+        .iter()
+        .map(|device| device.friendly_name.clone())
+        .collect();
+    let mut kept_indexes: Vec<usize> = Vec::new();
+    let mut index = 0;
+
+    config.devices.retain_mut(|device| {
+        let field = |name: &str| format!("devices[{index}].{name}");
+        let mut device_problems: Vec<ConfigProblem> = Vec::new();
+
+        match friendly_names.get(&device.friendly_name) {
+            Some(first_index) => device_problems.push(ConfigProblem::error(
+                &field("friendly_name"),
+                format!(
+                    "Duplicate friendly name '{}' (first used by devices[{first_index}])",
+                    device.friendly_name
+                ),
+            )),
+            None => {
+                friendly_names.insert(device.friendly_name.clone(), index);
+            }
+        }
+
+        match MacAddressSource::try_from(device.mac_address.clone()) {
+            Ok(source) => device.mac_source = Some(source),
+            Err(e) => device_problems.push(ConfigProblem::error(
+                &field("mac_address"),
+                format!("Expected 'arp', 'dhcp' or a MAC address: {e}"),
+            )),
+        }
+
+        if device.ups.is_empty() {
+            if ups_names.len() == 1 {
+                device.ups = ups_names.clone();
+            } else if ups_names.len() > 1 {
+                device_problems.push(ConfigProblem::error(
+                    &field("ups"),
+                    String::from(
+                        "A UPS must be given when more than one UPS is configured",
+                    ),
+                ));
+            }
+        }
+
+        for ups in &device.ups {
+            if !ups_names.contains(ups) {
+                device_problems.push(ConfigProblem::error(
+                    &field("ups"),
+                    format!("Unknown UPS '{ups}'"),
+                ));
+            }
+        }
+
+        for dependency in &device.depends_on {
+            if *dependency == device.friendly_name {
+                device_problems.push(ConfigProblem::error(
+                    &field("depends_on"),
+                    String::from("A device can't depend on itself"),
+                ));
+            } else if !all_friendly_names.contains(dependency) {
+                device_problems.push(ConfigProblem::error(
+                    &field("depends_on"),
+                    format!("Unknown device '{dependency}'"),
+                ));
+            }
+        }
+
+        let has_wol_overrides = device.wol.min_battery_percentage.is_some()
+            || device.wol.restore_delay.is_some()
+            || device.wol.restore_timeout.is_some()
+            || device.wol.reattempt_delay.is_some();
+
+        if has_wol_overrides {
+            device_problems.append(&mut validate_wol(
+                &global_wol.with_overrides(&device.wol),
+                &field("wol"),
+            ));
+        }
+
+        let has_liveness_overrides = device.liveness.successes_to_online.is_some()
+            || device.liveness.failures_to_offline.is_some()
+            || device.liveness.online_lookback.is_some();
+
+        if has_liveness_overrides {
+            device_problems.append(&mut validate_liveness(
+                &global_liveness.with_overrides(&device.liveness),
+                &field("liveness"),
+            ));
+        }
+
+        device_problems.append(&mut validate_check(&device.check, &field("check")));
+
+        let host = device.host.clone();
+
+        if host.is_none() && device.resolves_mac_address() {
+            device_problems.push(ConfigProblem::error(
+                &field("host"),
+                format!(
+                    "A host is required to resolve the MAC address of '{}' through '{}'",
+                    device.friendly_name, device.mac_address
+                ),
+            ));
+        } else if device.mac_source == Some(MacAddressSource::Dhcp) && mac_sources.is_empty() {
+            device_problems.push(ConfigProblem::error(
+                &field("mac_address"),
+                String::from("'dhcp' requires at least one file in 'mac_sources'"),
+            ));
+        } else if let Some(host) = &host
+            && let Some(source) = device.mac_source
+            && device.resolves_mac_address()
+        {
+            match resolve_device_mac_address(source, host, &mac_sources) {
+                Ok(mac_address) => {
+                    device.resolved_mac_address = Some(mac_address);
+                }
+                Err(e) => match get_cached_mac_address(&device.friendly_name) {
+                    // The device is likely still offline after an outage, so use the MAC address it had last time
+                    Some(mac_address) => {
+                        device_problems.push(ConfigProblem::warning(
+                            &field("mac_address"),
+                            format!(
+                                "Unable to resolve MAC address for '{}': {e}; using the last known MAC address {mac_address}",
+                                device.friendly_name
+                            ),
+                        ));
+                        device.resolved_mac_address = Some(mac_address);
+                    }
+                    // Keep the device with a pending MAC address, which is learned once it responds
+                    None => device_problems.push(ConfigProblem::warning(
+                        &field("mac_address"),
+                        format!(
+                            "Unable to resolve MAC address for '{}': {e}; it will be resolved once it is available (MAC pending)",
+                            device.friendly_name
+                        ),
+                    )),
+                },
+            }
+        } else if check_reachability {
+            match find_device_address(device, &mac_sources) {
+                Some(address) if !is_device_online(&address, &device.check, &ping_config) => {
+                    device_problems.push(ConfigProblem::warning(
+                        &field("host"),
+                        format!("'{address}' did not respond to {}", device.check),
+                    ));
+                }
+                Some(_) => {}
+                None => device_problems.push(ConfigProblem::warning(
+                    &field("mac_address"),
+                    format!(
+                        "No IP address found for '{}' in the neighbor table or MAC sources",
+                        device.friendly_name
+                    ),
+                )),
+            }
+        }
+
+        if let Some(mac_address) = device.known_mac_address() {
+            match mac_addresses.get(&mac_address) {
+                Some(first_index) => device_problems.push(ConfigProblem::error(
+                    &field("mac_address"),
+                    format!("Duplicate MAC address {mac_address} (first used by devices[{first_index}])"),
+                )),
+                None => {
+                    mac_addresses.insert(mac_address, index);
+                }
+            }
+        }
+
+        let keep_device = strict || !has_errors(&device_problems);
+
+        if keep_device {
+            kept_indexes.push(index);
+        }
+        index += 1;
+
+        if !keep_device {
+            for problem in device_problems.iter_mut() {
+                if problem.severity == Severity::Error {
+                    problem.severity = Severity::Warning;
+                    problem.message.push_str(" (device skipped)");
+                }
+            }
+        }
+
+        problems.append(&mut device_problems);
+
+        return keep_device;
+    });
+
+    problems.append(&mut validate_dependencies(&config.devices, &kept_indexes));
+
+    return problems;
+}
+
+/// `validate_dependencies()` rejects dependency cycles between `devices` and warns about dependencies on skipped devices
+///
+/// `indexes` holds the index of each device in the configuration file, for reporting
+fn validate_dependencies(devices: &[DeviceConfig], indexes: &[usize]) -> Vec<ConfigProblem> {
+    let mut problems: Vec<ConfigProblem> = Vec::new();
+    let devices: Vec<&DeviceConfig> = devices.iter().collect();
+
+    for (device, index) in devices.iter().zip(indexes) {
+        for dependency in &device.depends_on {
+            if !devices
+                .iter()
+                .any(|device| device.friendly_name == *dependency)
+            {
+                problems.push(ConfigProblem::warning(
+                    &format!("devices[{index}].depends_on"),
+                    format!("Depends on '{dependency}', which was skipped: '{}' will be reported as blocked", device.friendly_name),
+                ));
+            }
+        }
+    }
+
+    let (_, unplaced) = wake_stages(&devices);
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+
+    for friendly_name in unplaced {
+        let Some(cycle) = find_cycle(&devices, &friendly_name) else {
+            continue;
+        };
+
+        // Every device in (or depending on) the same cycle finds it, only report it once
+        let mut members = cycle[1..].to_vec();
+        members.sort();
+        if cycles.contains(&members) {
+            continue;
+        }
+        cycles.push(members);
+
+        let index = devices
+            .iter()
+            .position(|device| device.friendly_name == cycle[0])
+            .map_or(0, |position| indexes[position]);
+
+        problems.push(ConfigProblem::error(
+            &format!("devices[{index}].depends_on"),
+            format!("Dependency cycle: {}", cycle.join(" -> ")),
+        ));
+    }
+
+    return problems;
+}
@@ -1,10 +1,11 @@
 use log::{debug, error, info};
 use std::io::Result;
 use std::net::Ipv4Addr;
-use std::str::FromStr;
 
-pub fn wakeonlan(mac: &str, friendly_name: &str) -> Result<()> {
-    let mac_address = wol::MacAddr6::from_str(mac).unwrap();
+use crate::mac::MacAddress;
+
+pub fn wakeonlan(mac: MacAddress, friendly_name: &str) -> Result<()> {
+    let mac_address = wol::MacAddr6::from(mac.octets());
 
     info!(target: "WoL", "Attempting to wake {friendly_name}");
     debug!(target: "WoL", "Bounded {mac} with {friendly_name}");
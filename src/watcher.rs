@@ -0,0 +1,44 @@
+use std::fs::metadata;
+use std::io::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// The `ConfigWatcher` struct detects when the configuration file should be reloaded, either because it was modified or because the process received SIGHUP
+pub struct ConfigWatcher {
+    path: String,
+    modified: Option<SystemTime>,
+    reload_requested: Arc<AtomicBool>,
+}
+
+/// `get_modified_time()` returns the last modification time of the file at `path`, or `None` if it can't be read
+fn get_modified_time(path: &str) -> Option<SystemTime> {
+    return metadata(path).and_then(|metadata| metadata.modified()).ok();
+}
+
+impl ConfigWatcher {
+    /// `ConfigWatcher::new()` starts watching the configuration file at `path` and registers a SIGHUP handler
+    pub fn new(path: &str) -> Result<Self> {
+        let reload_requested = Arc::new(AtomicBool::new(false));
+
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone())?;
+
+        return Ok(ConfigWatcher {
+            path: path.to_string(),
+            modified: get_modified_time(path),
+            reload_requested,
+        });
+    }
+
+    /// `should_reload()` returns true once for every modification of the configuration file or SIGHUP received since it was last called
+    pub fn should_reload(&mut self) -> bool {
+        let signaled = self.reload_requested.swap(false, Ordering::Relaxed);
+
+        let modified = get_modified_time(&self.path);
+        let changed = modified.is_some() && modified != self.modified;
+        self.modified = modified;
+
+        return signaled || changed;
+    }
+}